@@ -0,0 +1,242 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use git2::{ObjectType, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use tar::{Builder, Header};
+use zip::{
+    write::{SimpleFileOptions, StreamWriter},
+    CompressionMethod, DateTime, ZipWriter,
+};
+
+use crate::{folders::FOLDER_MARKER, history::days_from_civil, utils::Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "tar.gz" | "tgz" => Some(Format::TarGz),
+            "zip" => Some(Format::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::TarGz => "tar.gz",
+            Format::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::TarGz => "application/gzip",
+            Format::Zip => "application/zip",
+        }
+    }
+}
+
+/// An archive written front to back, the output never needs to seek
+pub enum Archive<W: Write> {
+    TarGz(Builder<GzEncoder<W>>, u64),
+    Zip(ZipWriter<StreamWriter<W>>, SimpleFileOptions),
+}
+
+/// Zip keeps local time split in fields, years before 1980 can not be written
+fn zip_time(mtime: u64) -> DateTime {
+    let days = (mtime / 86_400) as i64;
+    let seconds = mtime % 86_400;
+    // no year is longer than 366 days, the ones left are counted up to
+    let mut year = 1970 + days / 366;
+    while days_from_civil(year + 1, 1, 1) <= days {
+        year += 1;
+    }
+    let month = (1..=12)
+        .rev()
+        .find(|month| days_from_civil(year, *month, 1) <= days)
+        .unwrap_or(1);
+    let day = days - days_from_civil(year, month, 1) + 1;
+    DateTime::from_date_and_time(
+        u16::try_from(year).unwrap_or_default(),
+        month as u8,
+        day as u8,
+        (seconds / 3600) as u8,
+        (seconds % 3600 / 60) as u8,
+        (seconds % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+impl<W: Write> Archive<W> {
+    /// `mtime` is seconds since the epoch, used for every entry
+    pub fn new(format: Format, out: W, mtime: u64) -> Archive<W> {
+        match format {
+            Format::TarGz => Archive::TarGz(
+                Builder::new(GzEncoder::new(out, Compression::default())),
+                mtime,
+            ),
+            Format::Zip => Archive::Zip(
+                ZipWriter::new_stream(out),
+                SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .last_modified_time(zip_time(mtime))
+                    .large_file(true)
+                    .unix_permissions(0o644),
+            ),
+        }
+    }
+
+    /// Tar wants the size up front, so the content is read from `data` only once `size` is known
+    pub fn append(&mut self, path: &Path, size: u64, data: &mut impl Read) -> Result<()> {
+        match self {
+            Archive::TarGz(builder, mtime) => {
+                let mut header = Header::new_gnu();
+                header.set_size(size);
+                header.set_mode(0o644);
+                header.set_mtime(*mtime);
+                builder.append_data(&mut header, path, data)?;
+            }
+            Archive::Zip(writer, options) => {
+                writer.start_file_from_path(path, *options)?;
+                io::copy(data, writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<W> {
+        match self {
+            Archive::TarGz(builder, _) => Ok(builder.into_inner()?.finish()?),
+            Archive::Zip(writer, _) => Ok(writer.finish()?.into_inner()),
+        }
+    }
+}
+
+/// Adds every file of a git tree under `prefix`, one blob in memory at a time
+pub fn append_tree<W: Write>(
+    archive: &mut Archive<W>,
+    repo: &Repository,
+    tree: &Tree,
+    prefix: &str,
+) -> Result<()> {
+    let mut failure = None;
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() != Some(ObjectType::Blob) {
+            return TreeWalkResult::Ok;
+        }
+        let Some(name) = entry.name() else {
+            return TreeWalkResult::Ok;
+        };
+        let path = Path::new(prefix).join(dir).join(name);
+        let result = repo
+            .find_blob(entry.id())
+            .map_err(Into::into)
+            .and_then(|blob| {
+                let content = blob.content();
+                archive.append(&path, content.len() as u64, &mut &content[..])
+            });
+        match result {
+            Ok(()) => TreeWalkResult::Ok,
+            Err(e) => {
+                failure = Some(e);
+                TreeWalkResult::Abort
+            }
+        }
+    })?;
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Adds the files under `dir` as they are on disk. Hidden entries stay out, the git repository,
+/// the drafts of every user and the files half written or set aside, but not the folder markers.
+/// Each file is read whole first, so one rewritten meanwhile can not disagree with its header.
+pub fn append_dir<W: Write>(archive: &mut Archive<W>, dir: &Path, prefix: &str) -> Result<()> {
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let mut entries = fs::read_dir(dir.join(&relative))?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name();
+            if name.to_string_lossy().starts_with('.') && name != FOLDER_MARKER {
+                continue;
+            }
+            let path = relative.join(&name);
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                let content = fs::read(entry.path())?;
+                let size = content.len() as u64;
+                archive.append(&Path::new(prefix).join(path), size, &mut content.as_slice())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::test_repo;
+    use flate2::read::GzDecoder;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_tree_to_tar_gz() {
+        let dir = test_repo("archive", "doc.md", "# Doc\n");
+        let repo = Repository::open(&dir).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let mut archive = Archive::new(Format::TarGz, Vec::new(), 1_700_000_000);
+        append_tree(&mut archive, &repo, &tree, "snapshot").unwrap();
+        let bytes = archive.finish().unwrap();
+
+        let mut archive = tar::Archive::new(GzDecoder::new(bytes.as_slice()));
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("snapshot/doc.md"));
+        assert_eq!(entry.header().mtime().unwrap(), 1_700_000_000);
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "# Doc\n");
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_zip() {
+        let dir = test_repo("archive-zip", "doc.md", "# Doc\n");
+        let repo = Repository::open(&dir).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let mut archive = Archive::new(Format::Zip, Vec::new(), 1_700_000_000);
+        append_tree(&mut archive, &repo, &tree, "backup").unwrap();
+        let bytes = archive.finish().unwrap();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(zip.len(), 1);
+        let mut entry = zip.by_name("backup/doc.md").unwrap();
+        // 2023-11-14 22:13:20 UTC, zip keeps even seconds only
+        let modified = entry.last_modified().unwrap();
+        assert_eq!(
+            (modified.year(), modified.month(), modified.day()),
+            (2023, 11, 14)
+        );
+        assert_eq!((modified.hour(), modified.minute()), (22, 13));
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "# Doc\n");
+
+        // the last second of a leap day
+        let leap = zip_time(951_868_799);
+        assert_eq!((leap.year(), leap.month(), leap.day()), (2000, 2, 29));
+        assert_eq!((leap.hour(), leap.minute()), (23, 59));
+    }
+}
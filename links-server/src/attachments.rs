@@ -0,0 +1,529 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use git2::{ObjectType, Oid, Repository, Signature, TreeWalkMode, TreeWalkResult};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use lib_hyper_organizator::response_utils::{read_full_body, IntoResultHyperResponse};
+use regex::Regex;
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::{
+    documents::TRASH_DIR,
+    folders, git_worker, locks,
+    router::{verify_user, verify_uuid, LinksError, CONFIG},
+    save_to_git::{self, write_atomic},
+    utils::{get_user_name, parse_query, Result},
+};
+
+/// Attachments live in the storage directory, one subdirectory per document
+pub const ATTACHMENTS_DIR: &str = "attachments";
+
+/// Uploads younger than this are kept even when nothing refers to them yet,
+/// the document is usually saved a little after the upload
+const GC_GRACE: Duration = Duration::from_secs(24 * 3600);
+
+/// The types we serve, anything else is a download
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("pdf", "application/pdf"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("zip", "application/zip"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+const OCTET_STREAM: &str = "application/octet-stream";
+
+lazy_static! {
+    /// The url of an attachment, as it appears in the markdown
+    static ref ATTACHMENT_URL: Regex = Regex::new(
+        r#"/attachments/([\da-f]{8}-(?:[\da-f]{4}-){3}[\da-f]{12})/([\da-f]{40}\.[a-z\d]{1,8})"#
+    )
+    .unwrap();
+}
+
+#[derive(Serialize, Debug)]
+struct Uploaded {
+    url:      String,
+    /// ready to paste into the document
+    markdown: String,
+    revision: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Collected {
+    removed:  Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+}
+
+pub fn mime_of(extension: &str) -> &'static str {
+    MIME_TYPES
+        .iter()
+        .find(|(e, _)| *e == extension)
+        .map_or(OCTET_STREAM, |(_, mime)| mime)
+}
+
+/// The extension for an upload, from its name or else from its content type
+fn extension_of(name: Option<&str>, content_type: Option<&str>) -> &'static str {
+    let from_name = name
+        .and_then(|name| Path::new(name).extension())
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .and_then(|e| MIME_TYPES.iter().find(|(known, _)| *known == e));
+    let from_type = || {
+        let content_type = content_type?.split(';').next()?.trim();
+        MIME_TYPES.iter().find(|(_, mime)| *mime == content_type)
+    };
+    from_name.or_else(from_type).map_or("bin", |(e, _)| e)
+}
+
+/// Stores the content under its git hash, so uploading it again gives the same url.
+/// Returns the url and the commit, which is HEAD when the attachment was already there.
+pub fn store(
+    repo_dir: &str,
+    uuid: &str,
+    content: &[u8],
+    extension: &str,
+    author: &Signature,
+) -> Result<(String, Oid)> {
+    folders::document_path(repo_dir, uuid)?;
+    let hash = Oid::hash_object(ObjectType::Blob, content)?;
+    let path = format!("{}/{}/{}.{}", ATTACHMENTS_DIR, uuid, hash, extension);
+    let url = format!("/{}", path);
+    let file = Path::new(repo_dir).join(&path);
+    // a file left on disk by a failed commit is written and committed again
+    let repo = Repository::open(Path::new(repo_dir))?;
+    if let Ok(head) = repo.head().and_then(|head| head.peel_to_commit()) {
+        if head.tree()?.get_path(Path::new(&path)).is_ok() {
+            return Ok((url, head.id()));
+        }
+    }
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_atomic(&file, content)?;
+    let message = format!("attached {}.{} to {}", hash, extension, uuid);
+    match save_to_git::commit(repo_dir, &[&path], author, &message) {
+        Ok(revision) => Ok((url, revision)),
+        Err(e) => {
+            let _ = fs::remove_file(&file);
+            save_to_git::unstage(repo_dir, &[&path]);
+            Err(e.into())
+        }
+    }
+}
+
+fn add_references(text: &str, referenced: &mut HashSet<String>) {
+    for url in ATTACHMENT_URL.find_iter(text) {
+        referenced.insert(url.as_str().trim_start_matches('/').to_string());
+    }
+}
+
+/// The documents of every commit reachable from a branch or a tag, the snapshots and the open
+/// proposals included, so old revisions keep their pictures. Shared trees and blobs are read once.
+fn add_history_references(repo: &Repository, referenced: &mut HashSet<String>) -> Result<()> {
+    let mut walk = repo.revwalk()?;
+    if let Ok(head) = repo.head().and_then(|head| head.peel_to_commit()) {
+        walk.push(head.id())?;
+    }
+    for reference in repo.references()? {
+        if let Ok(commit) = reference?.peel_to_commit() {
+            walk.push(commit.id())?;
+        }
+    }
+    let mut seen = HashSet::new();
+    for id in walk {
+        let tree = repo.find_commit(id?)?.tree()?;
+        if !seen.insert(tree.id()) {
+            continue;
+        }
+        tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+            if !seen.insert(entry.id()) {
+                return TreeWalkResult::Skip;
+            }
+            if entry.kind() == Some(ObjectType::Blob)
+                && entry.name().is_some_and(|name| name.ends_with(".md"))
+            {
+                if let Ok(blob) = repo.find_blob(entry.id()) {
+                    add_references(&String::from_utf8_lossy(blob.content()), referenced);
+                }
+            }
+            TreeWalkResult::Ok
+        })?;
+    }
+    Ok(())
+}
+
+/// Everything that may still refer to an attachment: the documents in every folder, those in
+/// the trash, the drafts and everything in the history
+fn referenced(repo_dir: &str, drafts_dir: &str) -> Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+    for document in folders::read_tree(repo_dir)?.all_documents() {
+        add_references(
+            &fs::read_to_string(Path::new(repo_dir).join(&document.path))?,
+            &mut referenced,
+        );
+    }
+    let mut dirs = vec![Path::new(repo_dir).join(TRASH_DIR)];
+    if let Ok(users) = fs::read_dir(drafts_dir) {
+        dirs.extend(users.flatten().map(|user| user.path()));
+    }
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if matches!(extension, Some("md") | Some("json")) && path.is_file() {
+                add_references(&fs::read_to_string(&path)?, &mut referenced);
+            }
+        }
+    }
+
+    add_history_references(&Repository::open(Path::new(repo_dir))?, &mut referenced)?;
+    Ok(referenced)
+}
+
+/// Removes the attachments older than `grace` that nothing refers to, in one commit
+fn put_back(aside: &[(PathBuf, PathBuf)]) {
+    for (file, set_aside) in aside {
+        if let Err(e) = fs::rename(set_aside, file) {
+            error!("could not put back {}: {}", file.display(), e);
+        }
+    }
+}
+
+pub fn collect_garbage(
+    repo_dir: &str,
+    drafts_dir: &str,
+    grace: Duration,
+    author: &Signature,
+) -> Result<(Vec<String>, Option<Oid>)> {
+    let referenced = referenced(repo_dir, drafts_dir)?;
+    let attachments_dir = Path::new(repo_dir).join(ATTACHMENTS_DIR);
+    let Ok(documents) = fs::read_dir(&attachments_dir) else {
+        return Ok((Vec::new(), None));
+    };
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+    let mut candidates = Vec::new();
+    for document in documents.flatten() {
+        let Ok(files) = fs::read_dir(document.path()) else {
+            continue;
+        };
+        for file in files.flatten() {
+            let path = format!(
+                "{}/{}/{}",
+                ATTACHMENTS_DIR,
+                document.file_name().to_string_lossy(),
+                file.file_name().to_string_lossy()
+            );
+            let age = file
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            if referenced.contains(&path) || age < grace {
+                continue;
+            }
+            removed.push(path);
+            candidates.push(file.path());
+        }
+    }
+    if removed.is_empty() {
+        return Ok((removed, None));
+    }
+
+    // set aside until the commit went through, a failed commit puts them back
+    let mut aside = Vec::new();
+    for file in candidates {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let set_aside = file.with_file_name(format!(".{}.gc", name));
+        if let Err(e) = fs::rename(&file, &set_aside) {
+            put_back(&aside);
+            return Err(e.into());
+        }
+        aside.push((file, set_aside));
+    }
+    removed.sort();
+    let paths: Vec<&str> = removed.iter().map(String::as_str).collect();
+    let message = format!("removed {} unreferenced attachments", removed.len());
+    let revision = match save_to_git::commit(repo_dir, &paths, author, &message) {
+        Ok(revision) => revision,
+        Err(e) => {
+            error!(
+                "commit of the collected attachments failed, putting them back: {}",
+                e
+            );
+            put_back(&aside);
+            save_to_git::unstage(repo_dir, &paths);
+            return Err(e.into());
+        }
+    };
+    for (file, set_aside) in &aside {
+        let _ = fs::remove_file(set_aside);
+        // only succeeds once the directory is empty
+        if let Some(dir) = file.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+    Ok((removed, Some(revision)))
+}
+
+fn error_response(
+    what: &str,
+    e: lib_hyper_organizator::typedef::GenericError,
+) -> Result<Response<Body>> {
+    match e.downcast_ref() {
+        Some(LinksError::BadUuid(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::BAD_REQUEST),
+        Some(LinksError::DocumentNotFound(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Some(LinksError::NotOwner(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        _ => {
+            error!("Failed to {}: {}", what, e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// `POST /attachments?uuid&name=screenshot.png` with the file as the body.
+/// The type comes from the extension of the name, or else from the `Content-Type` header.
+pub async fn upload_attachment(mut request: Request<Body>) -> Result<Response<Body>> {
+    let too_large = request
+        .headers()
+        .get("Content-Length")
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|length| length > CONFIG.max_attachment_size);
+    if too_large {
+        return "attachment too large".to_text_response_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let (Some(uuid), params) = parse_query(request.uri().query()) else {
+        return "no document supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let uuid = String::from(uuid);
+    let name = params.get("name").cloned();
+    let content_type = request
+        .headers()
+        .get("Content-Type")
+        .and_then(|t| t.to_str().ok());
+    let extension = extension_of(name.as_deref(), content_type);
+    let cn = String::from(get_user_name(&request)?);
+    // nothing is read from those who could not store it anyway
+    if !CONFIG.is_owner(&cn) {
+        return error_response("store the attachment", Box::new(LinksError::NotOwner(cn)));
+    }
+    if let Err(e) = verify_uuid(&uuid) {
+        return error_response("store the attachment", e);
+    }
+    let whole_body = read_full_body(&mut request).await?;
+    if whole_body.len() > CONFIG.max_attachment_size {
+        return "attachment too large".to_text_response_with_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    if whole_body.is_empty() {
+        return "empty attachment".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    info!(
+        "{} attaches {} bytes of {} to {}",
+        cn,
+        whole_body.len(),
+        extension,
+        uuid
+    );
+
+    let _guard = locks::lock_document(&uuid).await;
+    let document = uuid.clone();
+    let result = git_worker::run(move || {
+        let user = verify_user(&cn)?;
+        store(
+            &CONFIG.storage_dir,
+            &document,
+            &whole_body,
+            extension,
+            &CONFIG.signature(user)?,
+        )
+    })
+    .await;
+    match result {
+        Ok((url, revision)) => {
+            let text = name.unwrap_or_else(|| String::from("attachment"));
+            let text = text.replace('[', "(").replace(']', ")");
+            let markdown = if mime_of(extension).starts_with("image/") {
+                format!("![{}]({})", text, url)
+            } else {
+                format!("[{}]({})", text, url)
+            };
+            let uploaded = Uploaded {
+                url,
+                markdown,
+                revision: revision.to_string(),
+            };
+            Ok(Response::builder()
+                .status(StatusCode::CREATED)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&uploaded)?))?)
+        }
+        Err(e) => error_response("store the attachment", e),
+    }
+}
+
+/// `/attachments/<uuid>/<hash>.<extension>`, never changes so it can be cached for good
+pub async fn get_attachment(req: Request<Body>) -> Result<Response<Body>> {
+    let path = req.uri().path().trim_start_matches('/');
+    let is_attachment = ATTACHMENT_URL
+        .find(req.uri().path())
+        .is_some_and(|url| url.as_str() == req.uri().path());
+    if !is_attachment {
+        return "invalid path".to_text_response_with_status(StatusCode::NOT_FOUND);
+    }
+    let extension = path.rsplit('.').next().unwrap_or_default();
+    match tokio::fs::read(Path::new(&CONFIG.storage_dir).join(path)).await {
+        Ok(content) => Ok(Response::builder()
+            .header("Content-Type", mime_of(extension))
+            .header("Cache-Control", "private, max-age=31536000, immutable")
+            // uploads are not trusted to run scripts, svg included
+            .header("Content-Security-Policy", "sandbox")
+            .header("X-Content-Type-Options", "nosniff")
+            .body(Body::from(content))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            "attachment not found".to_text_response_with_status(StatusCode::NOT_FOUND)
+        }
+        Err(e) => error_response("read the attachment", e.into()),
+    }
+}
+
+/// Owners remove the attachments no document, draft or proposal refers to any more
+pub async fn gc_attachments(request: Request<Body>) -> Result<Response<Body>> {
+    let cn = String::from(get_user_name(&request)?);
+    if !CONFIG.is_owner(&cn) {
+        return error_response(
+            "collect the attachments",
+            Box::new(LinksError::NotOwner(cn)),
+        );
+    }
+    // nothing is saved meanwhile, a document could start referring to an attachment being removed
+    let _guard = locks::lock_working_tree().await;
+    let result = git_worker::run(move || {
+        let user = verify_user(&cn)?;
+        collect_garbage(
+            &CONFIG.storage_dir,
+            &CONFIG.drafts_dir(),
+            GC_GRACE,
+            &CONFIG.signature(user)?,
+        )
+    })
+    .await;
+    match result {
+        Ok((removed, revision)) => {
+            info!("removed {} attachments", removed.len());
+            let collected = Collected {
+                removed,
+                revision: revision.map(|r| r.to_string()),
+            };
+            serde_json::to_string(&collected)?.to_json_response()
+        }
+        Err(e) => error_response("collect the attachments", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::test_repo;
+
+    #[test]
+    fn test_attachments() {
+        assert_eq!(extension_of(Some("Shot.PNG"), None), "png");
+        assert_eq!(extension_of(None, Some("image/jpeg; q=1")), "jpg");
+        assert_eq!(extension_of(Some("notes"), Some("x/unknown")), "bin");
+        assert_eq!(mime_of("bin"), OCTET_STREAM);
+
+        let uuid = "8b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let file_name = format!("{}.md", uuid);
+        let dir = test_repo("attachments", &file_name, "# Doc\n");
+        let author = Signature::now("alice", "_").unwrap();
+        let missing = "00000000-0000-4000-8000-000000000000";
+        assert!(store(&dir, missing, b"x", "png", &author).is_err());
+
+        let (kept, revision) = store(&dir, uuid, b"kept", "png", &author).unwrap();
+        let (again, same) = store(&dir, uuid, b"kept", "png", &author).unwrap();
+        assert_eq!((&kept, revision), (&again, same));
+        assert!(ATTACHMENT_URL.is_match(&kept));
+        let (dropped, _) = store(&dir, uuid, b"dropped", "pdf", &author).unwrap();
+        // only an older revision refers to it
+        let (old, _) = store(&dir, uuid, b"old", "png", &author).unwrap();
+        fs::write(
+            Path::new(&dir).join(&file_name),
+            format!("# Doc\n![old]({})\n", old),
+        )
+        .unwrap();
+        save_to_git::commit(&dir, &[&file_name], &author, "old picture").unwrap();
+        fs::write(
+            Path::new(&dir).join(&file_name),
+            format!("# Doc\n![shot]({})\n", kept),
+        )
+        .unwrap();
+
+        let drafts = format!("{}-drafts", dir);
+        let (removed, revision) = collect_garbage(&dir, &drafts, GC_GRACE, &author).unwrap();
+        assert!(removed.is_empty() && revision.is_none());
+        let (removed, revision) = collect_garbage(&dir, &drafts, Duration::ZERO, &author).unwrap();
+        assert_eq!(removed, vec![dropped.trim_start_matches('/').to_string()]);
+        assert!(revision.is_some());
+        assert!(Path::new(&dir).join(kept.trim_start_matches('/')).is_file());
+        assert!(Path::new(&dir).join(old.trim_start_matches('/')).is_file());
+        assert!(!Path::new(&dir)
+            .join(dropped.trim_start_matches('/'))
+            .exists());
+        let repo = Repository::open(&dir).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree
+            .get_path(Path::new(dropped.trim_start_matches('/')))
+            .is_err());
+
+        // a failed commit leaves the attachment where it was
+        let (orphan, _) = store(&dir, uuid, b"orphan", "png", &author).unwrap();
+        let orphan = Path::new(&dir).join(orphan.trim_start_matches('/'));
+        let index_lock = Path::new(&dir).join(".git/index.lock");
+        fs::write(&index_lock, "").unwrap();
+        assert!(collect_garbage(&dir, &drafts, Duration::ZERO, &author).is_err());
+        fs::remove_file(&index_lock).unwrap();
+        assert!(orphan.is_file());
+        let (removed, _) = collect_garbage(&dir, &drafts, Duration::ZERO, &author).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(!orphan.exists());
+
+        // on disk but never committed, storing it again commits it
+        let hash = Oid::hash_object(ObjectType::Blob, b"stray").unwrap();
+        let stray = format!("{}/{}/{}.png", ATTACHMENTS_DIR, uuid, hash);
+        fs::write(Path::new(&dir).join(&stray), "stray").unwrap();
+        let before = save_to_git::head_revision(&dir).unwrap();
+        let (_, committed) = store(&dir, uuid, b"stray", "png", &author).unwrap();
+        assert_ne!(committed.to_string(), before);
+        let tree = repo.find_commit(committed).unwrap().tree().unwrap();
+        assert!(tree.get_path(Path::new(&stray)).is_ok());
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use git2::{Oid, Repository};
+use hyper::{body::Sender, Body, Request, Response, StatusCode};
+use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
+use tokio::runtime::Handle;
+use tracing::{error, info};
+
+use crate::{
+    archive::{self, Archive, Format},
+    git_worker, locks,
+    router::{err, LinksError, CONFIG},
+    utils::{get_user_name, parse_query, Result},
+};
+
+/// The body is handed to hyper in chunks of this size
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Feeds a streaming response body from a blocking thread.
+/// Unless `finish` is called the body is aborted, so a failed archive never looks complete.
+pub(crate) struct BodyWriter {
+    sender:  Option<Sender>,
+    buffer:  Vec<u8>,
+    runtime: Handle,
+}
+
+impl BodyWriter {
+    fn new(sender: Sender) -> BodyWriter {
+        BodyWriter {
+            sender:  Some(sender),
+            buffer:  Vec::with_capacity(CHUNK_SIZE),
+            runtime: Handle::current(),
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        let Some(sender) = self.sender.as_mut() else {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        };
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(CHUNK_SIZE),
+        ));
+        self.runtime
+            .block_on(sender.send_data(chunk))
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.send()?;
+        // dropping the sender ends the body
+        self.sender.take();
+        Ok(())
+    }
+}
+
+impl Drop for BodyWriter {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            sender.abort();
+        }
+    }
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+/// A response body written while it is sent, by `write` on a blocking thread.
+/// Not on the git workers, a slow download would hold one of them for its whole length.
+pub(crate) fn streamed_body<F>(what: &'static str, write: F) -> Body
+where
+    F: FnOnce(BodyWriter) -> Result<BodyWriter> + Send + 'static,
+{
+    let (sender, body) = Body::channel();
+    let out = BodyWriter::new(sender);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = write(out).and_then(|out| Ok(out.finish()?)) {
+            error!("Failed to write the {}: {}", what, e);
+        }
+    });
+    body
+}
+
+/// A file in the temp directory, removed when dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(what: &str) -> TempFile {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        TempFile(std::env::temp_dir().join(format!(
+            "links-{}-{}-{}",
+            what,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        )))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Writes what `git bundle create --all` would: the refs, then one pack with everything they reach.
+/// The server runs without a git binary, so the v2 bundle format is written by hand.
+pub fn write_bundle(repo: &Repository, head: Oid, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "# v2 git bundle")?;
+    let mut packbuilder = repo.packbuilder()?;
+    let mut walk = repo.revwalk()?;
+    for reference in repo.references()? {
+        let reference = reference?;
+        let (Some(name), Some(id)) = (reference.name(), reference.target()) else {
+            continue;
+        };
+        // refs to anything but commits, like tags of a single blob, are not ours
+        let Ok(commit) = reference.peel_to_commit() else {
+            continue;
+        };
+        writeln!(out, "{} {}", id, name)?;
+        // annotated tags are objects of their own, the walk only adds commits and trees
+        if id != commit.id() {
+            packbuilder.insert_object(id, None)?;
+        }
+        walk.push(commit.id())?;
+    }
+    writeln!(out, "{} HEAD", head)?;
+    writeln!(out)?;
+    walk.push(head)?;
+    packbuilder.insert_walk(&mut walk)?;
+
+    let mut failure = None;
+    packbuilder.foreach(|chunk| match out.write_all(chunk) {
+        Ok(()) => true,
+        Err(e) => {
+            failure = Some(e);
+            false
+        }
+    })?;
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+/// The storage directory under `prefix/`, uncommitted files like the click log included,
+/// and with `history` also `prefix.bundle` with everything up to `head`.
+/// The caller keeps the syncs out, they would rewrite the directory while it is read.
+pub fn write_backup<W: Write>(
+    repo_dir: &str,
+    head: Oid,
+    format: Format,
+    history: bool,
+    prefix: &str,
+    out: W,
+) -> Result<W> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut archive = Archive::new(format, out, mtime);
+    archive::append_dir(&mut archive, Path::new(repo_dir), prefix)?;
+    if history {
+        // tar needs the size before the content, so the bundle goes through a file
+        let bundle = TempFile::new("bundle");
+        let mut file = BufWriter::new(File::create(&bundle.0)?);
+        write_bundle(&repo, head, &mut file)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        let size = fs::metadata(&bundle.0)?.len();
+        let path = PathBuf::from(format!("{}.bundle", prefix));
+        archive.append(&path, size, &mut File::open(&bundle.0)?)?;
+    }
+    archive.finish()
+}
+
+fn head_of(repo_dir: &str) -> Result<Oid> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = match repo.head() {
+        Ok(head) => head.peel_to_commit()?.id(),
+        Err(_) => return err!(LinksError::RevisionNotFound(String::from("HEAD"))),
+    };
+    Ok(head)
+}
+
+fn prefix_of(head: Oid) -> String {
+    format!("links-{:.7}", head.to_string())
+}
+
+/// The whole backup in a temp file, so the working tree is only held while it is read from disk
+fn build_backup(repo_dir: &str, format: Format, history: bool) -> Result<(Oid, TempFile)> {
+    let head = head_of(repo_dir)?;
+    let archive = TempFile::new("backup");
+    let out = BufWriter::new(File::create(&archive.0)?);
+    let out = write_backup(repo_dir, head, format, history, &prefix_of(head), out)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok((head, archive))
+}
+
+/// `/backup?format=zip&history=true`, the format defaults to `tar.gz`. Only for the owners.
+/// The archive is built with the syncs kept out and sent once they can go on again,
+/// the `X-Head-Revision` header names the commit the files were at.
+pub async fn get_backup(req: Request<Body>) -> Result<Response<Body>> {
+    let (_, params) = parse_query(req.uri().query());
+    let Some(format) = Format::from_name(params.get("format").map_or("tar.gz", String::as_str))
+    else {
+        return "format is tar.gz or zip".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let history = params.get("history").is_some_and(|h| h == "true");
+    let user = get_user_name(&req)?;
+    if !CONFIG.is_owner(user) {
+        return LinksError::NotOwner(String::from(user))
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+
+    let reading = locks::read_working_tree().await;
+    let built = git_worker::run(move || build_backup(&CONFIG.storage_dir, format, history)).await;
+    drop(reading);
+    let (head, archive) = match built {
+        Ok(built) => built,
+        Err(e) if matches!(e.downcast_ref(), Some(LinksError::RevisionNotFound(_))) => {
+            return "nothing committed yet".to_text_response_with_status(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            error!("Failed to build the backup: {}", e);
+            return e
+                .to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    info!(
+        "{} downloads a backup of {}, history {}",
+        user, head, history
+    );
+
+    let file_name = format!("{}.{}", prefix_of(head), format.extension());
+    let body = streamed_body("backup", move |mut out| {
+        io::copy(&mut File::open(&archive.0)?, &mut out)?;
+        Ok(out)
+    });
+
+    Ok(Response::builder()
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            format!(r#"attachment; filename="{}""#, file_name),
+        )
+        .header("X-Head-Revision", head.to_string())
+        .body(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::test_repo;
+    use flate2::read::GzDecoder;
+    use git2::Signature;
+    use std::io::Read;
+
+    #[test]
+    fn test_write_backup() {
+        let dir = test_repo("backup", "doc.md", "# Doc\n");
+        let repo = Repository::open(&dir).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let tagger = Signature::now("alice", "_").unwrap();
+        repo.tag("v1", head.as_object(), &tagger, "first", false)
+            .unwrap();
+
+        // never committed, still part of the backup
+        fs::write(Path::new(&dir).join("click.log"), "a\n").unwrap();
+        // hidden files stay out, the folder markers do not
+        fs::create_dir_all(Path::new(&dir).join(".drafts/alice")).unwrap();
+        fs::write(Path::new(&dir).join(".drafts/alice/doc.json"), "{}").unwrap();
+        fs::write(Path::new(&dir).join(".doc.md.tmp"), "# Half\n").unwrap();
+        fs::create_dir_all(Path::new(&dir).join("work")).unwrap();
+        fs::write(Path::new(&dir).join("work/.folder"), "").unwrap();
+
+        let bytes =
+            write_backup(&dir, head.id(), Format::TarGz, true, "links", Vec::new()).unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(bytes.as_slice()));
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("links/click.log"));
+        let mut log = String::new();
+        entry.read_to_string(&mut log).unwrap();
+        assert_eq!(log, "a\n");
+        let entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("links/doc.md"));
+        let entry = entries.next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("links/work/.folder"));
+        let mut bundle = entries.next().unwrap().unwrap();
+        assert_eq!(bundle.path().unwrap(), Path::new("links.bundle"));
+        let mut content = Vec::new();
+        bundle.read_to_end(&mut content).unwrap();
+        assert!(entries.next().is_none());
+
+        let separator = content.windows(2).position(|w| w == b"\n\n").unwrap();
+        let header = String::from_utf8_lossy(&content[..separator]);
+        let mut lines = header.lines();
+        assert_eq!(lines.next(), Some("# v2 git bundle"));
+        assert!(header.contains(&format!("{} HEAD", head.id())));
+        assert!(header.contains(" refs/tags/v1"));
+        assert_eq!(&content[separator + 2..separator + 6], b"PACK");
+    }
+}
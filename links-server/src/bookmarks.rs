@@ -0,0 +1,605 @@
+use std::{fs, path::Path};
+
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use lib_hyper_organizator::response_utils::{read_full_body, IntoResultHyperResponse};
+use regex::Regex;
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    diff::title_of,
+    documents::{self, Created, NewDocument},
+    folders::{self, FolderTree},
+    git_worker,
+    markdown::{extract_links, heading, leaves_first, push_heading, Sections},
+    router::{err, verify_uuid, LinksError, CONFIG},
+    utils::{get_user_name, parse_query, Result},
+};
+
+/// An entry of a bookmark file, in the order the browser wrote it
+#[derive(Debug, PartialEq)]
+pub enum Item {
+    Bookmark { title: String, url: String },
+    Folder(Folder),
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Folder {
+    pub title: String,
+    pub items: Vec<Item>,
+}
+
+#[derive(Serialize, Debug)]
+struct Imported {
+    /// the documents created, one per top level folder
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    created:   Vec<Created>,
+    /// the revision of the document appended to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision:  Option<String>,
+    bookmarks: usize,
+}
+
+/// Title for the bookmarks that are not in any folder
+const LOOSE_BOOKMARKS: &str = "Bookmarks";
+
+pub(crate) fn decode_entities(s: &str) -> String {
+    lazy_static! {
+        static ref ENTITY: Regex = Regex::new(r#"&(#[0-9]+|#x[0-9a-fA-F]+|[a-z]+);"#).unwrap();
+    }
+    ENTITY
+        .replace_all(s, |c: &regex::Captures| {
+            let entity = &c[1];
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                    None => entity[1..].parse().ok().and_then(char::from_u32),
+                },
+            };
+            decoded
+                .map(String::from)
+                .unwrap_or_else(|| c[0].to_string())
+        })
+        .into_owned()
+}
+
+/// Reads a Netscape bookmark file, the format every browser exports.
+/// The returned folder stands for the outermost list and has no title.
+pub fn parse_bookmarks(html: &str) -> Folder {
+    lazy_static! {
+        static ref TOKEN: Regex = Regex::new(
+            r#"(?is)<H3[^>]*>(.*?)</H3>|<DL[^>]*>|</DL>|<A\s[^>]*?HREF\s*=\s*"([^"]*)"[^>]*>(.*?)</A>"#
+        )
+        .unwrap();
+        static ref TAG: Regex = Regex::new(r#"<[^>]*>"#).unwrap();
+    }
+    let text = |s: &str| decode_entities(TAG.replace_all(s, "").trim());
+
+    // the folders still open, the outermost list at the bottom
+    let mut open: Vec<Folder> = Vec::new();
+    let mut heading = None;
+    let mut root = None;
+    for token in TOKEN.captures_iter(html) {
+        let whole = token[0].to_ascii_uppercase();
+        if let Some(title) = token.get(1) {
+            heading = Some(text(title.as_str()));
+        } else if let Some(url) = token.get(2) {
+            if let Some(folder) = open.last_mut() {
+                let url = decode_entities(url.as_str());
+                let title = text(&token[3]);
+                folder.items.push(Item::Bookmark {
+                    title: if title.is_empty() { url.clone() } else { title },
+                    url,
+                });
+            }
+        } else if whole.starts_with("</DL") {
+            let Some(folder) = open.pop() else {
+                continue;
+            };
+            match open.last_mut() {
+                Some(parent) => parent.items.push(Item::Folder(folder)),
+                None => root = Some(folder),
+            }
+        } else {
+            open.push(Folder {
+                title: heading.take().unwrap_or_default(),
+                items: Vec::new(),
+            });
+        }
+    }
+    // files cut short still give what was read so far
+    while let Some(folder) = open.pop() {
+        match open.last_mut() {
+            Some(parent) => parent.items.push(Item::Folder(folder)),
+            None => root = Some(folder),
+        }
+    }
+    root.unwrap_or_default()
+}
+
+/// Markdown does not allow brackets in the link text or spaces and parentheses in the url
+pub(crate) fn markdown_link(title: &str, url: &str) -> String {
+    let title = title.replace('[', "(").replace(']', ")");
+    let url = url
+        .replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29");
+    format!("[{}]({})", title, url)
+}
+
+/// The items of a folder as markdown, subfolders become headings starting at `level`.
+/// The bookmarks of a folder go before its subfolders.
+pub fn to_markdown(items: &[Item], level: usize) -> String {
+    let mut markdown = String::new();
+    for item in leaves_first(items, |item| matches!(item, Item::Bookmark { .. })) {
+        match item {
+            Item::Bookmark { title, url } => {
+                markdown.push_str(&format!("- {}\n", markdown_link(title, url)))
+            }
+            Item::Folder(folder) => {
+                push_heading(&mut markdown, level, &folder.title);
+                markdown.push_str(&to_markdown(&folder.items, level + 1));
+            }
+        }
+    }
+    markdown
+}
+
+pub fn count_bookmarks(items: &[Item]) -> usize {
+    items
+        .iter()
+        .map(|item| match item {
+            Item::Bookmark { .. } => 1,
+            Item::Folder(folder) => count_bookmarks(&folder.items),
+        })
+        .sum()
+}
+
+/// One new document per top level folder, the loose bookmarks go together in one more
+fn split_documents(root: Folder) -> Vec<NewDocument> {
+    let mut documents = Vec::new();
+    let mut loose = Vec::new();
+    for item in root.items {
+        match item {
+            Item::Folder(folder) => documents.push(NewDocument {
+                title:   Some(folder.title),
+                content: Some(to_markdown(&folder.items, 2)),
+                folder:  None,
+            }),
+            bookmark => loose.push(bookmark),
+        }
+    }
+    if !loose.is_empty() {
+        documents.push(NewDocument {
+            title:   Some(String::from(LOOSE_BOOKMARKS)),
+            content: Some(to_markdown(&loose, 2)),
+            folder:  None,
+        });
+    }
+    documents
+}
+
+pub(crate) fn encode_entities(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `memo_encrypt` in the editor replaces the whole page, title included, with its output, a run
+/// of base64 that the page shows cut short through `memo_rust::truncate_base64`. Wrapped by an
+/// editor or a mail client it becomes lines of the same length, only the last one shorter.
+/// Cipher text mixes cases and digits, a long word on its own line is still text.
+pub fn looks_encrypted(content: &str) -> bool {
+    let lines: Vec<&str> = content.trim().lines().map(str::trim_end).collect();
+    let Some((last, wrapped)) = lines.split_last() else {
+        return false;
+    };
+    let width = lines[0].len();
+    let encoded = |line: &&str| {
+        !line.is_empty()
+            && line
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '=' | '-' | '_'))
+    };
+    let has = |test: fn(&char) -> bool| lines.iter().any(|line| line.chars().any(|c| test(&c)));
+    lines.iter().map(|line| line.len()).sum::<usize>() >= 32
+        && lines.iter().all(encoded)
+        && has(char::is_ascii_uppercase)
+        && has(char::is_ascii_lowercase)
+        && has(char::is_ascii_digit)
+        && wrapped.iter().all(|line| line.len() == width)
+        && last.len() <= width
+}
+
+/// A document as a folder named after its title. Headings become subfolders and the links
+/// a browser can open become bookmarks, folders left without bookmarks are dropped.
+pub fn from_markdown(uuid: &str, content: &str) -> Folder {
+    let document = Folder {
+        title: String::from(title_of(uuid, content)),
+        items: Vec::new(),
+    };
+    let mut sections = Sections::new(document, |folder, parent: &mut Folder| {
+        if count_bookmarks(&folder.items) > 0 {
+            parent.items.push(Item::Folder(folder));
+        }
+    });
+    // the first line is the title, it names the document folder
+    for line in content.lines().skip(1) {
+        if let Some((level, text)) = heading(line) {
+            sections.open(
+                level,
+                Folder {
+                    title: decode_entities(text.trim_end_matches('#').trim()),
+                    items: Vec::new(),
+                },
+            );
+            continue;
+        }
+        let folder = sections.current();
+        for link in extract_links(line) {
+            if !link.url.contains("://") {
+                continue;
+            }
+            folder.items.push(Item::Bookmark {
+                title: if link.text.is_empty() {
+                    link.url.clone()
+                } else {
+                    link.text
+                },
+                url:   link.url,
+            });
+        }
+    }
+    sections.finish()
+}
+
+fn write_items(html: &mut String, items: &[Item], depth: usize) {
+    let indent = "    ".repeat(depth);
+    html.push_str(&format!("{}<DL><p>\n", indent));
+    for item in items {
+        match item {
+            Item::Bookmark { title, url } => html.push_str(&format!(
+                "{}    <DT><A HREF=\"{}\">{}</A>\n",
+                indent,
+                encode_entities(url),
+                encode_entities(title)
+            )),
+            Item::Folder(folder) => {
+                html.push_str(&format!(
+                    "{}    <DT><H3>{}</H3>\n",
+                    indent,
+                    encode_entities(&folder.title)
+                ));
+                write_items(html, &folder.items, depth + 1);
+            }
+        }
+    }
+    html.push_str(&format!("{}</DL><p>\n", indent));
+}
+
+/// Writes a Netscape bookmark file, the reverse of `parse_bookmarks`
+pub fn to_html(root: &Folder) -> String {
+    let mut html = String::from(indoc::indoc! {r#"
+        <!DOCTYPE NETSCAPE-Bookmark-file-1>
+        <META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+        <TITLE>Bookmarks</TITLE>
+        <H1>Bookmarks</H1>
+    "#});
+    write_items(&mut html, &root.items, 0);
+    html
+}
+
+/// The documents of a folder, one bookmark folder each, followed by its subfolders
+fn export_folder(tree: &FolderTree, encrypted: &mut Vec<String>) -> Result<Vec<Item>> {
+    let mut documents = Vec::new();
+    for document in &tree.documents {
+        let content = fs::read_to_string(Path::new(&CONFIG.storage_dir).join(&document.path))?;
+        if looks_encrypted(&content) {
+            encrypted.push(document.uuid.clone());
+            continue;
+        }
+        documents.push(from_markdown(&document.uuid, &content));
+    }
+    documents.sort_by_cached_key(|f| f.title.to_lowercase());
+    let mut items: Vec<Item> = documents.into_iter().map(Item::Folder).collect();
+    for folder in &tree.folders {
+        items.push(Item::Folder(Folder {
+            title: folder.name.clone(),
+            items: export_folder(folder, encrypted)?,
+        }));
+    }
+    Ok(items)
+}
+
+/// The documents in the storage directory, one folder each, and the uuids of the encrypted ones
+fn export(uuid: Option<&str>) -> Result<(Folder, Vec<String>)> {
+    let mut root = Folder::default();
+    let mut encrypted = Vec::new();
+    if let Some(uuid) = uuid {
+        let path = folders::document_path(&CONFIG.storage_dir, uuid)?;
+        let content = fs::read_to_string(Path::new(&CONFIG.storage_dir).join(path))?;
+        if looks_encrypted(&content) {
+            return err!(LinksError::DocumentEncrypted(String::from(uuid)));
+        }
+        root.items.push(Item::Folder(from_markdown(uuid, &content)));
+        return Ok((root, encrypted));
+    }
+
+    // like the catalog, the folders of the storage directory without the trash
+    let tree = folders::read_tree(&CONFIG.storage_dir)?;
+    root.items = export_folder(&tree, &mut encrypted)?;
+    encrypted.sort();
+    Ok((root, encrypted))
+}
+
+/// `GET /export_bookmarks` gives the whole catalog as a bookmark file,
+/// `GET /export_bookmarks?uuid` a single document.
+/// The encrypted documents left out are listed in the `X-Skipped-Encrypted` header.
+pub async fn export_bookmarks(req: Request<Body>) -> Result<Response<Body>> {
+    let uuid = parse_query(req.uri().query()).0.map(String::from);
+    match git_worker::run(move || export(uuid.as_deref())).await {
+        Ok((root, encrypted)) => {
+            if !encrypted.is_empty() {
+                warn!("{} encrypted documents not exported", encrypted.len());
+            }
+            Ok(Response::builder()
+                .header("Content-Type", "text/html; charset=UTF-8")
+                .header(
+                    "Content-Disposition",
+                    r#"attachment; filename="bookmarks.html""#,
+                )
+                .header("X-Skipped-Encrypted", encrypted.join(","))
+                .body(Body::from(to_html(&root)))?)
+        }
+        Err(e) => match e.downcast_ref() {
+            Some(LinksError::BadUuid(_)) => e
+                .to_string()
+                .to_text_response_with_status(StatusCode::BAD_REQUEST),
+            Some(LinksError::DocumentNotFound(_)) => e
+                .to_string()
+                .to_text_response_with_status(StatusCode::NOT_FOUND),
+            Some(LinksError::DocumentEncrypted(_)) => e
+                .to_string()
+                .to_text_response_with_status(StatusCode::UNPROCESSABLE_ENTITY),
+            _ => {
+                error!("Failed to export the bookmarks: {}", e);
+                e.to_string()
+                    .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+    }
+}
+
+/// `POST /import_bookmarks` with the html file as the body creates new documents,
+/// `POST /import_bookmarks?uuid` appends everything to an existing document
+pub async fn import_bookmarks(mut request: Request<Body>) -> Result<Response<Body>> {
+    let whole_body = read_full_body(&mut request).await?;
+    let html = String::from_utf8_lossy(&whole_body);
+    let root = parse_bookmarks(&html);
+    let bookmarks = count_bookmarks(&root.items);
+    if bookmarks == 0 {
+        return "no bookmarks found".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    let user = get_user_name(&request)?;
+    let (target, _) = parse_query(request.uri().query());
+    info!("{} imports {} bookmarks into {:?}", user, bookmarks, target);
+
+    let result = match target {
+        Some(uuid) => {
+            if verify_uuid(uuid).is_err() {
+                return "bad uuid".to_text_response_with_status(StatusCode::BAD_REQUEST);
+            }
+            let message = format!("imported {} bookmarks", bookmarks);
+            documents::append(uuid, to_markdown(&root.items, 2), user, message)
+                .await
+                .map(|saved| Imported {
+                    created: Vec::new(),
+                    revision: Some(saved.revision),
+                    bookmarks,
+                })
+        }
+        None => {
+            let mut created = Vec::new();
+            let mut failure = None;
+            for document in split_documents(root) {
+                match documents::create(document, user).await {
+                    Ok(document) => created.push(document),
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                }
+            }
+            match failure {
+                // the documents already created stay, the response says which ones they are
+                Some(e) if !created.is_empty() => {
+                    error!("import stopped after {} documents: {}", created.len(), e);
+                    Ok(Imported {
+                        created,
+                        revision: None,
+                        bookmarks,
+                    })
+                }
+                Some(e) => Err(e),
+                None => Ok(Imported {
+                    created,
+                    revision: None,
+                    bookmarks,
+                }),
+            }
+        }
+    };
+
+    match result {
+        Ok(imported) => Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&imported)?))?),
+        Err(e) => match e.downcast_ref() {
+            Some(LinksError::DocumentNotFound(_)) => e
+                .to_string()
+                .to_text_response_with_status(StatusCode::NOT_FOUND),
+            Some(LinksError::NotOwner(_)) => e
+                .to_string()
+                .to_text_response_with_status(StatusCode::FORBIDDEN),
+            _ => {
+                error!("Failed to import the bookmarks: {}", e);
+                e.to_string()
+                    .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORTED: &str = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks</H1>
+<DL><p>
+    <DT><H3 ADD_DATE="1700000000" PERSONAL_TOOLBAR_FOLDER="true">Toolbar</H3>
+    <DL><p>
+        <DT><A HREF="https://www.rust-lang.org/" ADD_DATE="1700000000">Rust &amp; [friends]</A>
+        <DT><H3>Docs</H3>
+        <DL><p>
+            <DT><A HREF="https://docs.rs/?q=a b">docs.rs</A>
+        </DL><p>
+    </DL><p>
+    <DT><A HREF="https://example.com/(x)"></A>
+</DL><p>
+"#;
+
+    #[test]
+    fn test_parse_bookmarks() {
+        let root = parse_bookmarks(EXPORTED);
+        assert_eq!(count_bookmarks(&root.items), 3);
+        assert_eq!(
+            root.items[1],
+            Item::Bookmark {
+                title: String::from("https://example.com/(x)"),
+                url:   String::from("https://example.com/(x)"),
+            }
+        );
+        let Item::Folder(toolbar) = &root.items[0] else {
+            panic!("expected the toolbar folder");
+        };
+        assert_eq!(toolbar.title, "Toolbar");
+        assert_eq!(
+            to_markdown(&toolbar.items, 2),
+            "- [Rust & (friends)](https://www.rust-lang.org/)\n\n## Docs\n\n- [docs.rs](https://docs.rs/?q=a%20b)\n"
+        );
+
+        let documents = split_documents(root);
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].title.as_deref(), Some("Toolbar"));
+        assert_eq!(documents[1].title.as_deref(), Some(LOOSE_BOOKMARKS));
+        assert_eq!(
+            documents[1].content.as_deref(),
+            Some("- [https://example.com/(x)](https://example.com/%28x%29)\n")
+        );
+    }
+
+    #[test]
+    fn test_bookmarks_round_trip() {
+        // a subfolder followed by a bookmark in the same folder
+        let html = indoc::indoc! {r#"
+            <DL><p>
+                <DT><H3>Reading</H3>
+                <DL><p>
+                    <DT><H3>Docs</H3>
+                    <DL><p>
+                        <DT><A HREF="https://docs.rs/">docs.rs</A>
+                    </DL><p>
+                    <DT><A HREF="https://www.rust-lang.org/">Rust</A>
+                </DL><p>
+            </DL><p>
+        "#};
+        let root = parse_bookmarks(html);
+        let documents = split_documents(root);
+        assert_eq!(documents.len(), 1);
+        let content = format!(
+            "# {}\n\n{}",
+            documents[0].title.as_deref().unwrap_or_default(),
+            documents[0].content.as_deref().unwrap_or_default()
+        );
+        let exported = Folder {
+            title: String::new(),
+            items: vec![Item::Folder(from_markdown("uuid", &content))],
+        };
+        let imported = parse_bookmarks(&to_html(&exported));
+
+        let Item::Folder(reading) = &imported.items[0] else {
+            panic!("expected the reading folder");
+        };
+        assert_eq!(reading.items.len(), 2);
+        assert_eq!(
+            reading.items[0],
+            Item::Bookmark {
+                title: String::from("Rust"),
+                url:   String::from("https://www.rust-lang.org/"),
+            }
+        );
+        let Item::Folder(docs) = &reading.items[1] else {
+            panic!("expected the docs folder");
+        };
+        assert_eq!(docs.title, "Docs");
+        assert_eq!(count_bookmarks(&docs.items), 1);
+    }
+
+    #[test]
+    fn test_export_bookmarks() {
+        let content = "# Reading\n\
+            - [Rust & co](https://www.rust-lang.org/) ![logo](https://a.b/logo.png)\n\
+            - [another page](/?8b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e)\n\
+            ## Empty\n\
+            ## Docs\n\
+            ### Std\n\
+            - [std](https://doc.rust-lang.org/std/)\n\
+            # Later\n\
+            - <https://example.com> [example](https://example.com)\n";
+        let document = from_markdown("uuid", content);
+        assert_eq!(document.title, "Reading");
+        assert_eq!(count_bookmarks(&document.items), 3);
+        assert_eq!(
+            to_markdown(&document.items, 2),
+            "- [Rust & co](https://www.rust-lang.org/)\n\n\
+            ## Docs\n\n### Std\n\n- [std](https://doc.rust-lang.org/std/)\n\n\
+            ## Later\n\n- [example](https://example.com)\n"
+        );
+
+        let root = Folder {
+            title: String::new(),
+            items: vec![Item::Folder(document)],
+        };
+        let html = to_html(&root);
+        assert!(html.contains("<DT><A HREF=\"https://www.rust-lang.org/\">Rust &amp; co</A>"));
+        assert_eq!(parse_bookmarks(&html), root);
+
+        // a nonce from the clock and 40 bytes of cipher text, as the editor puts them on the page
+        let encrypted = "AMAsyJkBAADsr+R7p3iBxucCLBGZdWfQCTb0pOPwZsZ0iQ/ebWICHJL8al4xSbnn";
+        assert!(looks_encrypted(encrypted));
+        assert!(looks_encrypted(&format!(
+            "{}\n{}\n",
+            &encrypted[..40],
+            &encrypted[40..]
+        )));
+        assert!(!looks_encrypted(content));
+        assert!(!looks_encrypted(
+            "Pneumonoultramicroscopicsilicovolcanoconiosis"
+        ));
+        assert!(!looks_encrypted(
+            "apples\nbananas\ncherries\ndates\nelderberries\n"
+        ));
+    }
+}
@@ -1,30 +1,82 @@
 use crate::utils::Result;
 use hyper::{Body, Request, Response, StatusCode};
 use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
-use tokio::fs::{read_dir, DirEntry};
+use tokio::fs::DirEntry;
 
-use crate::router::CONFIG;
+use crate::folders::{self, DocumentEntry, FolderTree};
+use crate::router::{LinksError, CONFIG};
+use crate::utils::parse_query;
+use crate::{git_worker, history};
+use git2::Repository;
+use std::path::Path;
 use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, BufReader};
 
-pub async fn get_catalog(_req: Request<Body>) -> Result<Response<Body>> {
-    match build_catalog(&CONFIG.storage_dir).await {
+pub async fn get_catalog(req: Request<Body>) -> Result<Response<Body>> {
+    let (_, params) = parse_query(req.uri().query());
+    let catalog = match params.get("at") {
+        Some(at) => {
+            let at = at.clone();
+            git_worker::run(move || build_catalog_at(&CONFIG.storage_dir, &at)).await
+        }
+        None => build_catalog(&CONFIG.storage_dir).await,
+    };
+    match catalog {
         Ok(catalog) => catalog.to_text_response(),
+        Err(e) if matches!(e.downcast_ref(), Some(LinksError::RevisionNotFound(_))) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
         Err(e) => e
             .to_string()
             .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
 
-fn trim(s: &str) -> &str {
+pub(crate) fn trim(s: &str) -> &str {
     // we allow titles inside comments
     s.trim_start_matches(|c| c == '\u{309B}' || c == '#' || c == ' ')
         .trim_matches(' ')
         .trim_end_matches(|c| c == '\n' || c == '\r' || c == ' ' || c == '\t')
 }
 
-async fn build_catalog(dir: &str) -> Result<String> {
-    let mut catalog = String::from(indoc::indoc! {r#"# Catalog
+/// The documents on top of the storage directory, then one section per folder,
+/// nested folders get deeper headings
+fn render_folder<F>(
+    catalog: &mut String,
+    tree: &FolderTree,
+    level: usize,
+    entry: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&DocumentEntry) -> Result<String>,
+{
+    let mut titles = tree
+        .documents
+        .iter()
+        .map(&mut *entry)
+        .collect::<Result<Vec<String>>>()?;
+    titles.sort_by_cached_key(|a| a.to_lowercase());
+    catalog.push_str(titles.join("\n").as_str());
+    for folder in &tree.folders {
+        if !catalog.ends_with("\n\n") {
+            catalog.push_str(if catalog.ends_with('\n') {
+                "\n"
+            } else {
+                "\n\n"
+            });
+        }
+        catalog.push_str(&format!("{} {}\n\n", "#".repeat(level.min(6)), folder.name));
+        render_folder(catalog, folder, level + 1, entry)?;
+    }
+    Ok(())
+}
+
+fn render_catalog<F>(heading: &str, tree: &FolderTree, mut entry: F) -> Result<String>
+where
+    F: FnMut(&DocumentEntry) -> Result<String>,
+{
+    let mut catalog = format!("# {}\n", heading);
+    catalog.push_str(indoc::indoc! {r#"
            <!-- 
              This file is generated by the server, do not edit it manually!
              To add a new entry to the catalog, create a new file in the data directory
@@ -34,27 +86,50 @@ async fn build_catalog(dir: &str) -> Result<String> {
            <link rel="stylesheet" href="/memo.css" >
            
         "#});
-    let mut titles = Vec::<String>::new();
-    let mut file_names = read_dir(dir).await?;
-    while let Some(dir_entry) = file_names.next_entry().await? {
-        let file_name = dir_entry.file_name();
-        let file_name = file_name.to_str().unwrap();
-        if file_name.ends_with(".md") {
-            let uuid = file_name.trim_end_matches(".md");
-            // read the first line in the file
-            if let Ok(line) = read_line(&dir_entry).await {
-                titles.push(format!("- [{}](/?{})", trim(&line), uuid));
-            } else {
-                titles.push(format!("- [{}](/?{})", uuid, uuid));
-            }
-        }
-    }
-    titles.sort_by_cached_key(|a| a.to_lowercase());
-    catalog.push_str(titles.join("\n").as_str());
+    render_folder(&mut catalog, tree, 2, &mut entry)?;
     Ok(catalog)
 }
 
-async fn read_line(dir_entry: &DirEntry) -> Result<String> {
+/// The first line of a file, where the title is
+fn first_line(path: &Path) -> Option<String> {
+    let file = std::fs::File::open(path).ok()?;
+    std::io::BufRead::lines(std::io::BufReader::new(file))
+        .next()?
+        .ok()
+}
+
+async fn build_catalog(dir: &str) -> Result<String> {
+    let dir = String::from(dir);
+    git_worker::run(move || {
+        // the folders of the storage directory, the trash and the attachments are left out
+        let tree = folders::read_tree(&dir)?;
+        render_catalog("Catalog", &tree, |document| {
+            let title = first_line(&Path::new(&dir).join(&document.path));
+            let title = title.as_deref().map(trim).unwrap_or(&document.uuid);
+            Ok(format!("- [{}](/?{})", title, document.uuid))
+        })
+    })
+    .await
+}
+
+/// The catalog as it was in an older revision, the entries link to the documents of that revision
+fn build_catalog_at(repo_dir: &str, at: &str) -> Result<String> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let id = history::resolve_revision(&repo, at)?;
+    let tree = repo.find_commit(id)?.tree()?;
+    let folders = folders::tree_at(&repo, &tree)?;
+    render_catalog(&format!("Catalog as of {}", at), &folders, |document| {
+        let blob = tree
+            .get_path(Path::new(&document.path))?
+            .to_object(&repo)?
+            .peel_to_blob()?;
+        let content = String::from_utf8_lossy(blob.content());
+        let title = content.lines().next().map(trim).unwrap_or(&document.uuid);
+        Ok(format!("- [{}](/?{}&at={})", title, document.uuid, id))
+    })
+}
+
+pub(crate) async fn read_line(dir_entry: &DirEntry) -> Result<String> {
     let file = File::open(dir_entry.path()).await?;
     let mut lines = BufReader::new(file).lines();
     if let Some(line) = lines.next_line().await? {
@@ -0,0 +1,226 @@
+use std::{collections::BTreeMap, path::Path};
+
+use git2::Patch;
+use hyper::{Body, Request, Response, StatusCode};
+use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::{
+    catalog::trim,
+    folders, git_worker, history,
+    markdown::{extract_links, Link},
+    router::{verify_uuid, LinksError, CONFIG},
+    utils::{parse_query, Result},
+};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LinkEntry {
+    pub url:  String,
+    pub text: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChangedLink {
+    pub url:      String,
+    pub old_text: String,
+    pub new_text: String,
+}
+
+/// What happened to the links of a document between two revisions
+#[derive(Serialize, Debug, PartialEq, Default)]
+pub struct LinkDiff {
+    pub added:   Vec<LinkEntry>,
+    pub removed: Vec<LinkEntry>,
+    pub changed: Vec<ChangedLink>,
+}
+
+/// Links are compared by url, when an url appears more than once the first anchor text counts
+fn links_by_url(content: &str) -> BTreeMap<String, String> {
+    let mut links = BTreeMap::new();
+    for Link { text, url } in extract_links(content) {
+        links.entry(url).or_insert(text);
+    }
+    links
+}
+
+pub fn link_diff(old: &str, new: &str) -> LinkDiff {
+    let old_links = links_by_url(old);
+    let mut new_links = links_by_url(new);
+    let mut diff = LinkDiff::default();
+    for (url, old_text) in old_links {
+        match new_links.remove(&url) {
+            Some(new_text) if new_text != old_text => diff.changed.push(ChangedLink {
+                url,
+                old_text,
+                new_text,
+            }),
+            Some(_) => (),
+            None => diff.removed.push(LinkEntry {
+                url,
+                text: old_text,
+            }),
+        }
+    }
+    diff.added = new_links
+        .into_iter()
+        .map(|(url, text)| LinkEntry { url, text })
+        .collect();
+    diff
+}
+
+/// The title of a document is its first line, the uuid stands in when there is none
+pub fn title_of<'a>(uuid: &'a str, content: &'a str) -> &'a str {
+    match content.lines().next().map(trim) {
+        Some(title) if !title.is_empty() => title,
+        _ => uuid,
+    }
+}
+
+fn count(what: &str, n: usize) -> String {
+    format!("{} {} link{}", what, n, if n == 1 { "" } else { "s" })
+}
+
+/// Commit message describing a save: the title and what happened to the links
+pub fn commit_summary(uuid: &str, old: &str, new: &str) -> String {
+    let diff = link_diff(old, new);
+    let mut counts = Vec::new();
+    if !diff.added.is_empty() {
+        counts.push(count("added", diff.added.len()));
+    }
+    if !diff.removed.is_empty() {
+        counts.push(count("removed", diff.removed.len()));
+    }
+    if !diff.changed.is_empty() {
+        counts.push(count("renamed", diff.changed.len()));
+    }
+    let title = title_of(uuid, new);
+    if counts.is_empty() {
+        return format!("{}: edited", title);
+    }
+
+    let mut message = format!("{}: {}\n\n", title, counts.join(", "));
+    for link in &diff.added {
+        message.push_str(&format!("+ {}\n", link.url));
+    }
+    for link in &diff.removed {
+        message.push_str(&format!("- {}\n", link.url));
+    }
+    for link in &diff.changed {
+        message.push_str(&format!("~ {}\n", link.url));
+    }
+    message
+}
+
+pub fn unified_diff(file_name: &str, old: &str, new: &str) -> Result<String> {
+    let path = Path::new(file_name);
+    let mut patch =
+        Patch::from_buffers(old.as_bytes(), Some(path), new.as_bytes(), Some(path), None)?;
+    let buf = patch.to_buf()?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// The two versions to compare, `to` defaults to the working copy
+fn versions(uuid: &str, from: &str, to: Option<&String>) -> Result<(String, String)> {
+    let (old, _) = history::document_at(&CONFIG.storage_dir, uuid, from)?;
+    let new = match to {
+        Some(to) => history::document_at(&CONFIG.storage_dir, uuid, to)?.0,
+        None => {
+            let path = folders::document_path(&CONFIG.storage_dir, uuid)?;
+            std::fs::read_to_string(Path::new(&CONFIG.storage_dir).join(path))?
+        }
+    };
+    Ok((old, new))
+}
+
+/// `/diff?uuid&from=rev&to=rev` as a unified diff, `/link_diff` with the same parameters as json
+pub async fn get_diff(req: Request<Body>, links: bool) -> Result<Response<Body>> {
+    let (Some(uuid), params) = parse_query(req.uri().query()) else {
+        return "no uuid supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    if verify_uuid(uuid).is_err() {
+        return "bad uuid".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    let Some(from) = params.get("from") else {
+        return "no from revision supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    info!("get_diff: {} from {} to {:?}", uuid, from, params.get("to"));
+
+    let (owned, from, to) = (String::from(uuid), from.clone(), params.get("to").cloned());
+    let (old, new) = match git_worker::run(move || versions(&owned, &from, to.as_ref())).await {
+        Ok(versions) => versions,
+        Err(e) => {
+            return match e.downcast_ref() {
+                Some(LinksError::DocumentNotFound(_)) | Some(LinksError::RevisionNotFound(_)) => e
+                    .to_string()
+                    .to_text_response_with_status(StatusCode::NOT_FOUND),
+                _ => {
+                    error!("Failed to compute the diff for {}: {}", uuid, e);
+                    e.to_string()
+                        .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        }
+    };
+
+    if links {
+        serde_json::to_string(&link_diff(&old, &new))?.to_json_response()
+    } else {
+        let diff = unified_diff(&format!("{}.md", uuid), &old, &new)?;
+        Ok(Response::builder()
+            .header("Content-Type", "text/x-diff")
+            .body(Body::from(diff))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_diff() {
+        let old = "- [Rust](https://rust-lang.org)\n- [Go](https://go.dev)\n- [C](https://c.org)\n";
+        let new = "- [Rust lang](https://rust-lang.org)\n- [C](https://c.org)\n- [Zig](https://ziglang.org)\n";
+        assert_eq!(
+            link_diff(old, new),
+            LinkDiff {
+                added:   vec![LinkEntry {
+                    url:  String::from("https://ziglang.org"),
+                    text: String::from("Zig"),
+                }],
+                removed: vec![LinkEntry {
+                    url:  String::from("https://go.dev"),
+                    text: String::from("Go"),
+                }],
+                changed: vec![ChangedLink {
+                    url:      String::from("https://rust-lang.org"),
+                    old_text: String::from("Rust"),
+                    new_text: String::from("Rust lang"),
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn test_commit_summary() {
+        let uuid = "329f4aef-f624-4ed1-8a89-bb9bb356a66a";
+        let old = "# Reading\n- [Go](https://go.dev)\n";
+        let new = "# Reading\n- [Rust](https://rust-lang.org)\n- [Zig](https://ziglang.org)\n";
+        assert_eq!(
+            commit_summary(uuid, old, new),
+            "Reading: added 2 links, removed 1 link\n\n+ https://rust-lang.org\n+ https://ziglang.org\n- https://go.dev\n"
+        );
+        assert_eq!(
+            commit_summary(uuid, old, "# Reading\nsome text\n- [Go](https://go.dev)\n"),
+            "Reading: edited"
+        );
+        assert_eq!(commit_summary(uuid, "", ""), format!("{}: edited", uuid));
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let diff = unified_diff("doc.md", "a\nb\n", "a\nc\n").unwrap();
+        assert!(diff.contains("--- a/doc.md\n+++ b/doc.md\n"));
+        assert!(diff.contains("-b\n+c\n"));
+    }
+}
@@ -0,0 +1,475 @@
+use std::{fs, path::Path};
+
+use bytes::Buf;
+use git2::{Oid, Signature};
+use hyper::{Body, Request, Response, StatusCode};
+use lib_hyper_organizator::response_utils::{parse_body, read_full_body, IntoResultHyperResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    catalog::{read_line, trim},
+    diff::title_of,
+    folders, git_worker, history, locks,
+    router::{err, verify_user, verify_uuid, LinksError, CONFIG},
+    save_to_git,
+    utils::{get_user_name, Result},
+};
+
+/// Deleted documents are moved here, inside the storage directory, so git sees a rename
+pub const TRASH_DIR: &str = "trash";
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct NewDocument {
+    #[serde(default)]
+    pub(crate) title:   Option<String>,
+    #[serde(default)]
+    pub(crate) content: Option<String>,
+    /// the folder the document goes in, the storage directory itself if missing
+    #[serde(default)]
+    pub(crate) folder:  Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Target {
+    uuid: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RevertTo {
+    uuid:     String,
+    revision: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Saved {
+    pub(crate) revision: String,
+}
+
+#[derive(Serialize, Debug)]
+struct TrashEntry {
+    uuid:  String,
+    title: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Created {
+    uuid:     String,
+    url:      String,
+    revision: String,
+}
+
+/// The title goes on the first line, that is where the catalog looks for it
+fn initial_content(doc: NewDocument) -> String {
+    let title = doc
+        .title
+        .map(|t| t.replace(['\n', '\r'], " ").trim().to_string())
+        .filter(|t| !t.is_empty());
+    let content = doc.content.unwrap_or_default();
+    match title {
+        Some(title) if content.is_empty() => format!("# {}\n", title),
+        Some(title) => format!("# {}\n\n{}", title, content),
+        None => content,
+    }
+}
+
+pub(crate) async fn create(doc: NewDocument, cn: &str) -> Result<Created> {
+    let uuid = Uuid::new_v4().to_string();
+    let _guard = locks::lock_document(&uuid).await;
+
+    let cn = String::from(cn);
+    git_worker::run(move || create_document(uuid, doc, &cn)).await
+}
+
+fn create_document(uuid: String, doc: NewDocument, cn: &str) -> Result<Created> {
+    let user = verify_user(cn)?;
+    if !CONFIG.is_owner(cn) {
+        return err!(LinksError::NotOwner(String::from(cn)));
+    }
+    let path = match doc.folder.as_deref().filter(|folder| !folder.is_empty()) {
+        Some(folder) => {
+            folders::verify_folder(folder)?;
+            if !Path::new(&CONFIG.storage_dir).join(folder).is_dir() {
+                return err!(LinksError::FolderNotFound(String::from(folder)));
+            }
+            format!("{}/{}.md", folder, uuid)
+        }
+        None => format!("{}.md", uuid),
+    };
+    let file_name = format!("{}/{}", CONFIG.storage_dir, path);
+    info!("creating {}", file_name);
+
+    // never overwrite, even if the impossible uuid collision happens
+    if Path::new(&file_name).exists() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, file_name).into());
+    }
+    let content = initial_content(doc);
+    let message = format!("created {}", title_of(&uuid, &content));
+    let revision = save_to_git::save(
+        &CONFIG.storage_dir,
+        &path,
+        &content,
+        None,
+        &CONFIG.signature(user)?,
+        &message,
+    )?;
+
+    Ok(Created {
+        url: format!("/?{}", uuid),
+        uuid,
+        revision: revision.to_string(),
+    })
+}
+
+pub async fn create_links(mut request: Request<Body>) -> Result<Response<Body>> {
+    let whole_body = read_full_body(&mut request).await?;
+    // an empty body creates an empty document
+    let doc: NewDocument = if whole_body.is_empty() {
+        NewDocument::default()
+    } else {
+        match serde_json::from_reader(whole_body.reader()) {
+            Ok(doc) => doc,
+            Err(e) => {
+                return format!("Error parsing json: {}", e)
+                    .to_text_response_with_status(StatusCode::BAD_REQUEST);
+            }
+        }
+    };
+
+    let user = get_user_name(&request)?;
+    match create(doc, user).await {
+        Ok(created) => Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Content-Type", "application/json")
+            .header("Location", &created.url)
+            .body(Body::from(serde_json::to_string(&created)?))?),
+        Err(e) if matches!(e.downcast_ref(), Some(LinksError::NotOwner(_))) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        Err(e) if matches!(e.downcast_ref(), Some(LinksError::BadFolderName(_))) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::BAD_REQUEST),
+        Err(e) if matches!(e.downcast_ref(), Some(LinksError::FolderNotFound(_))) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to create the document: {}", e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Moves a document between the storage directory and the trash and commits the move
+async fn move_document(uuid: &str, cn: &str, to_trash: bool) -> Result<Saved> {
+    let _guard = locks::lock_document(uuid).await;
+
+    let (uuid, cn) = (String::from(uuid), String::from(cn));
+    git_worker::run(move || rename_document(&uuid, &cn, to_trash)).await
+}
+
+fn rename_document(uuid: &str, cn: &str, to_trash: bool) -> Result<Saved> {
+    verify_uuid(uuid)?;
+    let user = verify_user(cn)?;
+    if !CONFIG.is_owner(cn) {
+        return err!(LinksError::NotOwner(String::from(cn)));
+    }
+    let author = CONFIG.signature(user)?;
+    let revision = move_trash(&CONFIG.storage_dir, uuid, to_trash, &author)?;
+    Ok(Saved {
+        revision: revision.to_string(),
+    })
+}
+
+/// Trashes a document from any folder, or restores it to the folder it was trashed from.
+/// The folder is kept next to it in the trash, a folder gone since means the top.
+fn move_trash(repo_dir: &str, uuid: &str, to_trash: bool, author: &Signature) -> Result<Oid> {
+    let storage_dir = Path::new(repo_dir);
+    let trash_dir = storage_dir.join(TRASH_DIR);
+    let file_name = format!("{}.md", uuid);
+    let trash_path = format!("{}/{}", TRASH_DIR, file_name);
+    let origin_path = format!("{}/{}.origin", TRASH_DIR, uuid);
+    let origin = storage_dir.join(&origin_path);
+    let folder = fs::read_to_string(&origin).ok();
+    let (from_path, to_path) = if to_trash {
+        (folders::document_path(repo_dir, uuid)?, trash_path)
+    } else {
+        let to_path = match folder.as_deref().map(str::trim) {
+            Some(folder)
+                if folders::verify_folder(folder).is_ok() && storage_dir.join(folder).is_dir() =>
+            {
+                format!("{}/{}", folder, file_name)
+            }
+            _ => file_name,
+        };
+        (trash_path, to_path)
+    };
+    let (from, to) = (storage_dir.join(&from_path), storage_dir.join(&to_path));
+    if !from.is_file() {
+        return err!(LinksError::DocumentNotFound(String::from(uuid)));
+    }
+    if to.exists() {
+        return Err(format!("{} already exists", to.display()).into());
+    }
+    info!("moving {} to {}", from.display(), to.display());
+
+    let content = fs::read_to_string(&from)?;
+    let title = title_of(uuid, &content);
+    let message = if to_trash {
+        format!("moved {} to trash", title)
+    } else {
+        format!("restored {} from trash", title)
+    };
+
+    fs::create_dir_all(&trash_dir)?;
+    fs::rename(&from, &to)?;
+    let recorded = if to_trash {
+        let (folder, _) = from_path.rsplit_once('/').unwrap_or_default();
+        fs::write(&origin, folder)
+    } else {
+        fs::remove_file(&origin).or_else(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })
+    };
+    // the content does not change, so git records a rename and the history follows
+    let paths = [from_path.as_str(), to_path.as_str(), origin_path.as_str()];
+    let committed = match recorded {
+        Ok(()) => save_to_git::commit(repo_dir, &paths, author, &message).map_err(Into::into),
+        Err(e) => Err(e.into()),
+    };
+    match committed {
+        Ok(revision) => Ok(revision),
+        Err(e) => {
+            fs::rename(&to, &from)?;
+            let _ = match &folder {
+                Some(folder) => fs::write(&origin, folder),
+                None => fs::remove_file(&origin),
+            };
+            save_to_git::unstage(repo_dir, &paths);
+            Err(e)
+        }
+    }
+}
+
+async fn move_links(mut request: Request<Body>, to_trash: bool) -> Result<Response<Body>> {
+    let target: Target = match parse_body(&mut request).await {
+        Ok(target) => target,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let user = get_user_name(&request)?;
+    let e = match move_document(&target.uuid, user, to_trash).await {
+        Ok(saved) => return serde_json::to_string(&saved)?.to_json_response(),
+        Err(e) => e,
+    };
+    match e.downcast_ref() {
+        Some(LinksError::DocumentNotFound(_)) | Some(LinksError::BadUuid(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Some(LinksError::NotOwner(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        _ => {
+            error!("Failed to move the document: {}", e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn delete_links(request: Request<Body>) -> Result<Response<Body>> {
+    move_links(request, true).await
+}
+
+pub async fn restore_links(request: Request<Body>) -> Result<Response<Body>> {
+    move_links(request, false).await
+}
+
+/// Puts back the content a document had in an older revision, as a new commit
+async fn revert(target: RevertTo, cn: &str) -> Result<Saved> {
+    let _guard = locks::lock_document(&target.uuid).await;
+
+    let cn = String::from(cn);
+    git_worker::run(move || revert_document(target, &cn)).await
+}
+
+fn revert_document(target: RevertTo, cn: &str) -> Result<Saved> {
+    verify_uuid(&target.uuid)?;
+    let user = verify_user(cn)?;
+    if !CONFIG.is_owner(cn) {
+        return err!(LinksError::NotOwner(String::from(cn)));
+    }
+    let path = folders::document_path(&CONFIG.storage_dir, &target.uuid)?;
+    let file_name = format!("{}/{}", CONFIG.storage_dir, path);
+    let current_content = fs::read_to_string(&file_name)?;
+    let (content, id) = history::document_at(&CONFIG.storage_dir, &target.uuid, &target.revision)?;
+    if content == current_content {
+        return err!(LinksError::ContentNotChanged);
+    }
+    info!("reverting {} to {}", file_name, id);
+
+    let revision = save_to_git::save(
+        &CONFIG.storage_dir,
+        &path,
+        &content,
+        Some(&current_content),
+        &CONFIG.signature(user)?,
+        &format!(
+            "{}: reverted to revision {}",
+            title_of(&target.uuid, &content),
+            id
+        ),
+    )?;
+    Ok(Saved {
+        revision: revision.to_string(),
+    })
+}
+
+/// Adds text at the end of a document, as a new commit
+pub(crate) async fn append(uuid: &str, text: String, cn: &str, message: String) -> Result<Saved> {
+    let _guard = locks::lock_document(uuid).await;
+
+    let (uuid, cn) = (String::from(uuid), String::from(cn));
+    git_worker::run(move || append_document(&uuid, &text, &cn, &message)).await
+}
+
+fn append_document(uuid: &str, text: &str, cn: &str, message: &str) -> Result<Saved> {
+    verify_uuid(uuid)?;
+    let user = verify_user(cn)?;
+    if !CONFIG.is_owner(cn) {
+        return err!(LinksError::NotOwner(String::from(cn)));
+    }
+    let path = folders::document_path(&CONFIG.storage_dir, uuid)?;
+    let current_content = fs::read_to_string(Path::new(&CONFIG.storage_dir).join(&path))?;
+    let content = format!("{}\n\n{}", current_content.trim_end(), text);
+    let revision = save_to_git::save(
+        &CONFIG.storage_dir,
+        &path,
+        &content,
+        Some(&current_content),
+        &CONFIG.signature(user)?,
+        &format!("{}: {}", title_of(uuid, &content), message),
+    )?;
+    Ok(Saved {
+        revision: revision.to_string(),
+    })
+}
+
+pub async fn revert_links(mut request: Request<Body>) -> Result<Response<Body>> {
+    let target: RevertTo = match parse_body(&mut request).await {
+        Ok(target) => target,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let user = get_user_name(&request)?;
+    let e = match revert(target, user).await {
+        Ok(saved) => return serde_json::to_string(&saved)?.to_json_response(),
+        Err(e) => e,
+    };
+    match e.downcast_ref() {
+        Some(LinksError::ContentNotChanged) => "Content is the same as in that revision"
+            .to_text_response_with_status(StatusCode::from_u16(254).unwrap()),
+        Some(LinksError::DocumentNotFound(_))
+        | Some(LinksError::RevisionNotFound(_))
+        | Some(LinksError::BadUuid(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Some(LinksError::NotOwner(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        _ => {
+            error!("Failed to revert the document: {}", e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Lists the deleted documents so they can be restored
+pub async fn get_trash(_req: Request<Body>) -> Result<Response<Body>> {
+    let trash_dir = Path::new(&CONFIG.storage_dir).join(TRASH_DIR);
+    let mut entries = Vec::new();
+    if let Ok(mut dir) = tokio::fs::read_dir(&trash_dir).await {
+        while let Some(dir_entry) = dir.next_entry().await? {
+            let file_name = dir_entry.file_name();
+            let Some(uuid) = file_name.to_str().and_then(|f| f.strip_suffix(".md")) else {
+                continue;
+            };
+            let title = match read_line(&dir_entry).await {
+                Ok(line) => trim(&line).to_string(),
+                Err(_) => uuid.to_string(),
+            };
+            entries.push(TrashEntry {
+                uuid: uuid.to_string(),
+                title,
+            });
+        }
+    }
+    entries.sort_by_cached_key(|e| e.title.to_lowercase());
+    serde_json::to_string(&entries)?.to_json_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::test_repo;
+
+    #[test]
+    fn test_initial_content() {
+        assert_eq!(initial_content(NewDocument::default()), "");
+        assert_eq!(
+            initial_content(NewDocument {
+                title:   Some(String::from(" Reading\nlist ")),
+                content: None,
+                folder:  None,
+            }),
+            "# Reading list\n"
+        );
+        assert_eq!(
+            initial_content(NewDocument {
+                title:   Some(String::from("Reading")),
+                content: Some(String::from("- [a](http://a)\n")),
+                folder:  None,
+            }),
+            "# Reading\n\n- [a](http://a)\n"
+        );
+        assert_eq!(
+            initial_content(NewDocument {
+                title:   None,
+                content: Some(String::from("just text")),
+                folder:  None,
+            }),
+            "just text"
+        );
+    }
+
+    #[test]
+    fn test_trash_and_restore() {
+        let uuid = "8b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let dir = test_repo("trash", &format!("{}.md", uuid), "# Doc\n");
+        let author = Signature::now("alice", "_").unwrap();
+        folders::create(&dir, "work", &author).unwrap();
+        folders::move_document(&dir, uuid, "work", &author).unwrap();
+
+        move_trash(&dir, uuid, true, &author).unwrap();
+        assert_eq!(folders::find_document(&dir, uuid), None);
+        move_trash(&dir, uuid, false, &author).unwrap();
+        let path = format!("work/{}.md", uuid);
+        assert_eq!(folders::find_document(&dir, uuid), Some(path));
+        let origin = format!("{}/{}.origin", TRASH_DIR, uuid);
+        assert!(!Path::new(&dir).join(origin).exists());
+
+        // the folder is gone meanwhile, the document comes back at the top
+        move_trash(&dir, uuid, true, &author).unwrap();
+        folders::rename(&dir, "work", "home", &author).unwrap();
+        move_trash(&dir, uuid, false, &author).unwrap();
+        let path = format!("{}.md", uuid);
+        assert_eq!(folders::find_document(&dir, uuid), Some(path));
+    }
+}
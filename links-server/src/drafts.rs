@@ -0,0 +1,250 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use hyper::{Body, Request, Response, StatusCode};
+use lib_hyper_organizator::response_utils::{parse_body, IntoResultHyperResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    diff::title_of,
+    git_worker,
+    router::{self, verify_user, verify_uuid, Payload, CONFIG},
+    save_to_git::write_atomic,
+    utils::{get_epoch_ms, get_user_name, parse_query, Result},
+};
+
+/// Work in progress on a document, autosaved by the editor and never committed
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Draft {
+    uuid:     String,
+    content:  String,
+    /// the git revision the editor loaded the document from, publishing merges against it
+    #[serde(default)]
+    revision: Option<String>,
+    /// epoch ms, set by the server
+    #[serde(default)]
+    updated:  u128,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct DraftEntry {
+    uuid:     String,
+    title:    String,
+    revision: Option<String>,
+    updated:  u128,
+}
+
+#[derive(Deserialize, Debug)]
+struct Publish {
+    uuid:    String,
+    /// commit message, generated from the changes when missing
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Target {
+    uuid: String,
+}
+
+fn draft_path(dir: &str, user: &str, uuid: &str) -> Result<PathBuf> {
+    // both end up in the path, so both are checked
+    verify_user(user)?;
+    verify_uuid(uuid)?;
+    Ok(Path::new(dir).join(user).join(format!("{}.json", uuid)))
+}
+
+fn store(dir: &str, user: &str, mut draft: Draft) -> Result<u128> {
+    let path = draft_path(dir, user, &draft.uuid)?;
+    fs::create_dir_all(Path::new(dir).join(user))?;
+    draft.updated = get_epoch_ms();
+    write_atomic(&path, &serde_json::to_string(&draft)?)?;
+    Ok(draft.updated)
+}
+
+fn load(dir: &str, user: &str, uuid: &str) -> Result<Option<Draft>> {
+    match fs::read_to_string(draft_path(dir, user, uuid)?) {
+        Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns false when there was no such draft
+fn discard(dir: &str, user: &str, uuid: &str) -> Result<bool> {
+    match fs::remove_file(draft_path(dir, user, uuid)?) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The open drafts of a user, most recent first
+fn list(dir: &str, user: &str) -> Result<Vec<DraftEntry>> {
+    verify_user(user)?;
+    let mut entries = Vec::new();
+    let Ok(files) = fs::read_dir(Path::new(dir).join(user)) else {
+        return Ok(entries);
+    };
+    for file in files {
+        let file_name = file?.file_name();
+        let Some(uuid) = file_name.to_str().and_then(|f| f.strip_suffix(".json")) else {
+            continue;
+        };
+        let Ok(Some(draft)) = load(dir, user, uuid) else {
+            continue;
+        };
+        entries.push(DraftEntry {
+            title:    title_of(&draft.uuid, &draft.content).to_string(),
+            uuid:     draft.uuid,
+            revision: draft.revision,
+            updated:  draft.updated,
+        });
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.updated));
+    Ok(entries)
+}
+
+fn bad_request(e: impl std::fmt::Display) -> Result<Response<Body>> {
+    format!("Error parsing json: {}", e).to_text_response_with_status(StatusCode::BAD_REQUEST)
+}
+
+fn failed(what: &str, e: lib_hyper_organizator::typedef::GenericError) -> Result<Response<Body>> {
+    error!("Failed to {}: {}", what, e);
+    e.to_string()
+        .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Autosave from the editor, overwrites the previous draft of the same document
+pub async fn save_draft(mut request: Request<Body>) -> Result<Response<Body>> {
+    let draft: Draft = match parse_body(&mut request).await {
+        Ok(draft) => draft,
+        Err(e) => return bad_request(e),
+    };
+    let user = String::from(get_user_name(&request)?);
+    match git_worker::run(move || store(&CONFIG.drafts_dir(), &user, draft)).await {
+        Ok(updated) => format!(r#"{{"updated":{}}}"#, updated).to_json_response(),
+        Err(e) => failed("save the draft", e),
+    }
+}
+
+/// `/draft?uuid`, the draft of the current user for the document
+pub async fn get_draft(req: Request<Body>) -> Result<Response<Body>> {
+    let (Some(uuid), _) = parse_query(req.uri().query()) else {
+        return "no uuid supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let (user, uuid) = (String::from(get_user_name(&req)?), String::from(uuid));
+    match git_worker::run(move || load(&CONFIG.drafts_dir(), &user, &uuid)).await {
+        Ok(Some(draft)) => serde_json::to_string(&draft)?.to_json_response(),
+        Ok(None) => "no draft".to_text_response_with_status(StatusCode::NOT_FOUND),
+        Err(e) => failed("read the draft", e),
+    }
+}
+
+pub async fn get_drafts(req: Request<Body>) -> Result<Response<Body>> {
+    let user = String::from(get_user_name(&req)?);
+    match git_worker::run(move || list(&CONFIG.drafts_dir(), &user)).await {
+        Ok(entries) => serde_json::to_string(&entries)?.to_json_response(),
+        Err(e) => failed("list the drafts", e),
+    }
+}
+
+pub async fn discard_draft(mut request: Request<Body>) -> Result<Response<Body>> {
+    let target: Target = match parse_body(&mut request).await {
+        Ok(target) => target,
+        Err(e) => return bad_request(e),
+    };
+    let user = String::from(get_user_name(&request)?);
+    match git_worker::run(move || discard(&CONFIG.drafts_dir(), &user, &target.uuid)).await {
+        Ok(true) => "Draft discarded".to_text_response(),
+        Ok(false) => "no draft".to_text_response_with_status(StatusCode::NOT_FOUND),
+        Err(e) => failed("discard the draft", e),
+    }
+}
+
+/// Saves the draft like the editor would and drops it once it made it into the document.
+/// A conflict leaves the draft in place so nothing is lost.
+pub async fn publish_draft(mut request: Request<Body>) -> Result<Response<Body>> {
+    let publish: Publish = match parse_body(&mut request).await {
+        Ok(publish) => publish,
+        Err(e) => return bad_request(e),
+    };
+    let user = get_user_name(&request)?;
+    let (owner, uuid) = (String::from(user), publish.uuid.clone());
+    let draft = match git_worker::run(move || load(&CONFIG.drafts_dir(), &owner, &uuid)).await {
+        Ok(Some(draft)) => draft,
+        Ok(None) => return "no draft".to_text_response_with_status(StatusCode::NOT_FOUND),
+        Err(e) => return failed("read the draft", e),
+    };
+    info!("publishing the draft of {} for {}", draft.uuid, user);
+
+    let response = router::save(
+        Payload {
+            uuid:     draft.uuid,
+            content:  draft.content,
+            revision: draft.revision,
+            message:  publish.message,
+        },
+        user,
+    )
+    .await?;
+    // 254, nothing changed, also means the document already has the draft content
+    if response.status().is_success() {
+        let owner = String::from(user);
+        if let Err(e) =
+            git_worker::run(move || discard(&CONFIG.drafts_dir(), &owner, &publish.uuid)).await
+        {
+            error!("published, but could not remove the draft: {}", e);
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drafts() {
+        let dir = std::env::temp_dir().join(format!("links-test-drafts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap();
+        let uuid = "329f4aef-f624-4ed1-8a89-bb9bb356a66a";
+        let draft = |content: &str| Draft {
+            uuid:     String::from(uuid),
+            content:  String::from(content),
+            revision: Some(String::from("abc")),
+            updated:  0,
+        };
+
+        assert!(list(dir, "alice").unwrap().is_empty());
+        store(dir, "alice", draft("# First\n")).unwrap();
+        let updated = store(dir, "alice", draft("# Second\n")).unwrap();
+        assert_eq!(
+            load(dir, "alice", uuid).unwrap(),
+            Some(Draft {
+                updated,
+                ..draft("# Second\n")
+            })
+        );
+        // drafts are per user
+        assert_eq!(load(dir, "bob", uuid).unwrap(), None);
+        assert_eq!(
+            list(dir, "alice").unwrap(),
+            vec![DraftEntry {
+                uuid: String::from(uuid),
+                title: String::from("Second"),
+                revision: Some(String::from("abc")),
+                updated,
+            }]
+        );
+
+        assert!(store(dir, "../alice", draft("x")).is_err());
+        assert!(discard(dir, "alice", uuid).unwrap());
+        assert!(!discard(dir, "alice", uuid).unwrap());
+        assert!(list(dir, "alice").unwrap().is_empty());
+    }
+}
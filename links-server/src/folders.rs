@@ -0,0 +1,676 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use git2::{ObjectType, Oid, Repository, Signature, Tree, TreeWalkMode, TreeWalkResult};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use lib_hyper_organizator::response_utils::{parse_body, IntoResultHyperResponse};
+use regex::Regex;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    attachments::ATTACHMENTS_DIR,
+    diff::title_of,
+    documents::{Saved, TRASH_DIR},
+    git_worker, locks,
+    router::{err, verify_user, verify_uuid, LinksError, CONFIG},
+    save_to_git,
+    utils::{get_user_name, Result},
+};
+
+/// Git does not keep empty directories, every folder holds this file so it survives until it is removed
+pub const FOLDER_MARKER: &str = ".folder";
+
+/// Folders nest at most this deep
+const MAX_DEPTH: usize = 16;
+
+lazy_static! {
+    /// The folders of HEAD for each storage directory, with the id of the tree they were read from
+    static ref TREES: Mutex<HashMap<String, (Oid, Arc<FolderTree>)>> = Mutex::new(HashMap::new());
+    /// Where the documents were last seen, only read again when one is not found there
+    static ref PATHS: Mutex<HashMap<String, HashMap<String, String>>> = Mutex::new(HashMap::new());
+}
+
+/// A folder of the storage directory with the documents and folders inside it
+#[derive(Debug, Default, PartialEq)]
+pub struct FolderTree {
+    /// the last component of the path, empty for the storage directory itself
+    pub name:      String,
+    /// relative to the storage directory, empty for the storage directory itself
+    pub path:      String,
+    pub documents: Vec<DocumentEntry>,
+    pub folders:   Vec<FolderTree>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DocumentEntry {
+    pub uuid: String,
+    /// relative to the storage directory, `folder/uuid.md`
+    pub path: String,
+}
+
+impl FolderTree {
+    /// The documents of this folder and all the folders below it
+    pub fn all_documents(&self) -> Vec<&DocumentEntry> {
+        let mut documents: Vec<&DocumentEntry> = self.documents.iter().collect();
+        for folder in &self.folders {
+            documents.extend(folder.all_documents());
+        }
+        documents
+    }
+
+    /// The paths of all the folders below this one, parents before their children
+    pub fn folder_paths(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        for folder in &self.folders {
+            paths.push(folder.path.clone());
+            paths.extend(folder.folder_paths());
+        }
+        paths
+    }
+
+    fn add(&mut self, name: &str, path: String) {
+        if let Some(uuid) = name.strip_suffix(".md") {
+            self.documents.push(DocumentEntry {
+                uuid: String::from(uuid),
+                path,
+            });
+        }
+    }
+
+    fn sort(&mut self) {
+        self.folders.sort_by_cached_key(|f| f.name.to_lowercase());
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct NewFolder {
+    path: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RenameFolder {
+    path: String,
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct MoveFolder {
+    path: String,
+    /// the new parent, empty for the storage directory itself
+    #[serde(default)]
+    to:   String,
+}
+
+#[derive(Deserialize, Debug)]
+struct MoveDocument {
+    uuid:   String,
+    /// empty for the storage directory itself
+    #[serde(default)]
+    folder: String,
+}
+
+/// Directories that hold something else than documents: git, the trash and the attachments
+fn reserved(name: &str) -> bool {
+    name.starts_with('.') || name == TRASH_DIR || name == ATTACHMENTS_DIR
+}
+
+/// Folder paths are relative, separated by `/`, and every name is kept simple
+pub fn verify_folder(path: &str) -> Result<()> {
+    lazy_static! {
+        static ref NAME: Regex = Regex::new(r#"^[\w][\w .,&()-]{0,63}$"#).unwrap();
+    }
+    let names: Vec<&str> = path.split('/').collect();
+    let valid = names.len() <= MAX_DEPTH
+        && names
+            .iter()
+            .all(|name| NAME.is_match(name) && !reserved(name) && name.trim_end() == *name);
+    if !valid {
+        return err!(LinksError::BadFolderName(String::from(path)));
+    }
+    Ok(())
+}
+
+fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn read_folder(repo_dir: &Path, path: &str, depth: usize) -> Result<FolderTree> {
+    let mut folder = FolderTree {
+        name: String::from(path.rsplit('/').next().unwrap_or_default()),
+        path: String::from(path),
+        ..FolderTree::default()
+    };
+    for entry in fs::read_dir(repo_dir.join(path))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && !reserved(&name) && depth < MAX_DEPTH {
+            folder
+                .folders
+                .push(read_folder(repo_dir, &join(path, &name), depth + 1)?);
+        } else if file_type.is_file() {
+            folder.add(&name, join(path, &name));
+        }
+    }
+    folder.sort();
+    Ok(folder)
+}
+
+fn head_tree(repo_dir: &str) -> Result<Arc<FolderTree>> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head()?.peel_to_tree()?;
+    let mut trees = TREES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((id, tree)) = trees.get(repo_dir) {
+        if *id == head.id() {
+            return Ok(tree.clone());
+        }
+    }
+    let tree = Arc::new(tree_at(&repo, &head)?);
+    trees.insert(String::from(repo_dir), (head.id(), tree.clone()));
+    Ok(tree)
+}
+
+/// The folders and documents of the storage directory, without the trash and the attachments.
+/// Everything is committed as soon as it is written, so they are read from HEAD and only again
+/// once HEAD moves. Before the first commit the directory itself is read.
+pub fn read_tree(repo_dir: &str) -> Result<Arc<FolderTree>> {
+    match head_tree(repo_dir) {
+        Ok(tree) => Ok(tree),
+        Err(_) => Ok(Arc::new(read_folder(Path::new(repo_dir), "", 0)?)),
+    }
+}
+
+/// Like [`read_tree`], for the tree of a commit
+pub fn tree_at(repo: &Repository, tree: &Tree) -> Result<FolderTree> {
+    fn read(repo: &Repository, tree: &Tree, path: &str, depth: usize) -> Result<FolderTree> {
+        let mut folder = FolderTree {
+            name: String::from(path.rsplit('/').next().unwrap_or_default()),
+            path: String::from(path),
+            ..FolderTree::default()
+        };
+        for entry in tree.iter() {
+            let Some(name) = entry.name() else {
+                continue;
+            };
+            match entry.kind() {
+                Some(ObjectType::Tree) if !reserved(name) && depth < MAX_DEPTH => {
+                    let subtree = repo.find_tree(entry.id())?;
+                    folder
+                        .folders
+                        .push(read(repo, &subtree, &join(path, name), depth + 1)?);
+                }
+                Some(ObjectType::Blob) => folder.add(name, join(path, name)),
+                _ => (),
+            }
+        }
+        folder.sort();
+        Ok(folder)
+    }
+    read(repo, tree, "", 0)
+}
+
+/// Drops what is known of the storage directory, for when HEAD moves under the saves
+pub(crate) fn forget(repo_dir: &str) {
+    TREES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(repo_dir);
+    PATHS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(repo_dir);
+}
+
+/// Where a document is in the storage directory, relative to it
+pub fn find_document(repo_dir: &str, uuid: &str) -> Option<String> {
+    let mut paths = PATHS.lock().unwrap_or_else(|e| e.into_inner());
+    let known = paths
+        .get(repo_dir)
+        .and_then(|documents| documents.get(uuid))
+        .filter(|path| Path::new(repo_dir).join(path).is_file());
+    if let Some(path) = known {
+        return Some(path.clone());
+    }
+    // created, moved or trashed since, or never there
+    let documents: HashMap<String, String> = read_tree(repo_dir)
+        .ok()?
+        .all_documents()
+        .into_iter()
+        .map(|document| (document.uuid.clone(), document.path.clone()))
+        .collect();
+    let path = documents.get(uuid).cloned();
+    paths.insert(String::from(repo_dir), documents);
+    path
+}
+
+/// Like [`find_document`], a missing document is an error
+pub fn document_path(repo_dir: &str, uuid: &str) -> Result<String> {
+    verify_uuid(uuid)?;
+    match find_document(repo_dir, uuid) {
+        Some(path) => Ok(path),
+        None => err!(LinksError::DocumentNotFound(String::from(uuid))),
+    }
+}
+
+/// The blob of a document in the tree of a commit and the path it is at, the trash included
+pub fn find_in_tree(tree: &Tree, uuid: &str) -> Option<(String, Oid)> {
+    let file_name = format!("{}.md", uuid);
+    let mut found = None;
+    let _ = tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        if dir.is_empty() && entry.name() == Some(ATTACHMENTS_DIR) {
+            return TreeWalkResult::Skip;
+        }
+        if entry.kind() == Some(ObjectType::Blob) && entry.name() == Some(file_name.as_str()) {
+            found = Some((format!("{}{}", dir, file_name), entry.id()));
+            return TreeWalkResult::Abort;
+        }
+        TreeWalkResult::Ok
+    });
+    found
+}
+
+/// A copy of the tree with the blob at `path`, the folders on the way are created as needed
+pub fn tree_with_blob(
+    repo: &Repository,
+    tree: Option<&Tree>,
+    path: &str,
+    blob: Oid,
+) -> Result<Oid> {
+    let mut builder = repo.treebuilder(tree)?;
+    match path.split_once('/') {
+        None => {
+            builder.insert(path, blob, 0o100644)?;
+        }
+        Some((name, rest)) => {
+            let subtree = match tree.and_then(|tree| tree.get_name(name)) {
+                Some(entry) if entry.kind() == Some(ObjectType::Tree) => {
+                    Some(repo.find_tree(entry.id())?)
+                }
+                _ => None,
+            };
+            let id = tree_with_blob(repo, subtree.as_ref(), rest, blob)?;
+            builder.insert(name, id, 0o040000)?;
+        }
+    }
+    Ok(builder.write()?)
+}
+
+/// The files below a directory, relative to it
+fn files_below(dir: &Path, prefix: &str, files: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = join(prefix, &entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            files_below(&entry.path(), &name, files)?;
+        } else {
+            files.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// Creates the folder, its parent has to exist already
+pub fn create(repo_dir: &str, path: &str, author: &Signature) -> Result<Oid> {
+    verify_folder(path)?;
+    let dir = Path::new(repo_dir).join(path);
+    if dir.exists() {
+        return err!(LinksError::FolderExists(String::from(path)));
+    }
+    if let Some((parent, _)) = path.rsplit_once('/') {
+        if !Path::new(repo_dir).join(parent).is_dir() {
+            return err!(LinksError::FolderNotFound(String::from(parent)));
+        }
+    }
+    fs::create_dir(&dir)?;
+    let marker = join(path, FOLDER_MARKER);
+    fs::write(Path::new(repo_dir).join(&marker), "")?;
+    let message = format!("created folder {}", path);
+    match save_to_git::commit(repo_dir, &[&marker], author, &message) {
+        Ok(id) => Ok(id),
+        Err(e) => {
+            let _ = fs::remove_dir_all(&dir);
+            save_to_git::unstage(repo_dir, &[&marker]);
+            Err(e.into())
+        }
+    }
+}
+
+/// Renames or moves a folder with everything in it, git follows the documents as renames
+pub fn rename(repo_dir: &str, from: &str, to: &str, author: &Signature) -> Result<Oid> {
+    verify_folder(from)?;
+    verify_folder(to)?;
+    if to == from || to.starts_with(&format!("{}/", from)) {
+        return err!(LinksError::BadFolderName(String::from(to)));
+    }
+    let (source, target) = (Path::new(repo_dir).join(from), Path::new(repo_dir).join(to));
+    if !source.is_dir() {
+        return err!(LinksError::FolderNotFound(String::from(from)));
+    }
+    if target.exists() {
+        return err!(LinksError::FolderExists(String::from(to)));
+    }
+    if let Some((parent, _)) = to.rsplit_once('/') {
+        if !Path::new(repo_dir).join(parent).is_dir() {
+            return err!(LinksError::FolderNotFound(String::from(parent)));
+        }
+    }
+    let mut files = Vec::new();
+    files_below(&source, "", &mut files)?;
+    fs::rename(&source, &target)?;
+
+    let mut paths: Vec<String> = files.iter().map(|file| join(from, file)).collect();
+    paths.extend(files.iter().map(|file| join(to, file)));
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    let same_parent = from.rsplit_once('/').map(|(p, _)| p) == to.rsplit_once('/').map(|(p, _)| p);
+    let message = if same_parent {
+        format!("renamed folder {} to {}", from, to)
+    } else {
+        format!("moved folder {} to {}", from, to)
+    };
+    match save_to_git::commit(repo_dir, &paths, author, &message) {
+        Ok(id) => Ok(id),
+        Err(e) => {
+            fs::rename(&target, &source)?;
+            save_to_git::unstage(repo_dir, &paths);
+            Err(e.into())
+        }
+    }
+}
+
+/// Moves a document into a folder, an empty folder being the storage directory itself
+pub fn move_document(repo_dir: &str, uuid: &str, folder: &str, author: &Signature) -> Result<Oid> {
+    let from = document_path(repo_dir, uuid)?;
+    if !folder.is_empty() {
+        verify_folder(folder)?;
+        if !Path::new(repo_dir).join(folder).is_dir() {
+            return err!(LinksError::FolderNotFound(String::from(folder)));
+        }
+    }
+    let to = join(folder, &format!("{}.md", uuid));
+    if to == from {
+        return err!(LinksError::ContentNotChanged);
+    }
+    let (source, target) = (
+        Path::new(repo_dir).join(&from),
+        Path::new(repo_dir).join(&to),
+    );
+    let content = fs::read_to_string(&source)?;
+    let destination = if folder.is_empty() { "the top" } else { folder };
+    let message = format!("moved {} to {}", title_of(uuid, &content), destination);
+    fs::rename(&source, &target)?;
+    match save_to_git::commit(repo_dir, &[&from, &to], author, &message) {
+        Ok(id) => Ok(id),
+        Err(e) => {
+            fs::rename(&target, &source)?;
+            save_to_git::unstage(repo_dir, &[&from, &to]);
+            Err(e.into())
+        }
+    }
+}
+
+fn error_response(
+    what: &str,
+    e: lib_hyper_organizator::typedef::GenericError,
+) -> Result<Response<Body>> {
+    match e.downcast_ref() {
+        Some(LinksError::BadFolderName(_))
+        | Some(LinksError::BadUuid(_))
+        | Some(LinksError::ContentNotChanged) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::BAD_REQUEST),
+        Some(LinksError::FolderNotFound(_)) | Some(LinksError::DocumentNotFound(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Some(LinksError::FolderExists(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::CONFLICT),
+        Some(LinksError::NotOwner(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        _ => {
+            error!("Failed to {}: {}", what, e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn saved_response(result: Result<Oid>, what: &str, status: StatusCode) -> Result<Response<Body>> {
+    match result {
+        Ok(revision) => {
+            let saved = Saved {
+                revision: revision.to_string(),
+            };
+            Ok(Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&saved)?))?)
+        }
+        Err(e) => error_response(what, e),
+    }
+}
+
+/// Folder changes move many documents at once, so they wait for every save to finish
+async fn change_folders<F>(
+    cn: String,
+    what: &str,
+    status: StatusCode,
+    change: F,
+) -> Result<Response<Body>>
+where
+    F: FnOnce(&str, &Signature) -> Result<Oid> + Send + 'static,
+{
+    if !CONFIG.is_owner(&cn) {
+        return error_response(what, Box::new(LinksError::NotOwner(cn)));
+    }
+    let _guard = locks::lock_working_tree().await;
+    let result = git_worker::run(move || {
+        let user = verify_user(&cn)?;
+        change(&CONFIG.storage_dir, &CONFIG.signature(user)?)
+    })
+    .await;
+    saved_response(result, what, status)
+}
+
+/// The paths of all the folders, parents first
+pub async fn get_folders(_req: Request<Body>) -> Result<Response<Body>> {
+    match git_worker::run(|| read_tree(&CONFIG.storage_dir)).await {
+        Ok(tree) => serde_json::to_string(&tree.folder_paths())?.to_json_response(),
+        Err(e) => error_response("list the folders", e),
+    }
+}
+
+/// `POST /folders` with `{"path": "work/reading"}`
+pub async fn create_folder(mut request: Request<Body>) -> Result<Response<Body>> {
+    let folder: NewFolder = match parse_body(&mut request).await {
+        Ok(folder) => folder,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let cn = String::from(get_user_name(&request)?);
+    info!("{} creates folder {}", cn, folder.path);
+    change_folders(
+        cn,
+        "create the folder",
+        StatusCode::CREATED,
+        move |repo_dir, author| create(repo_dir, &folder.path, author),
+    )
+    .await
+}
+
+/// `POST /rename_folder` with `{"path": "work/reading", "name": "later"}`
+pub async fn rename_folder(mut request: Request<Body>) -> Result<Response<Body>> {
+    let rename_to: RenameFolder = match parse_body(&mut request).await {
+        Ok(rename_to) => rename_to,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    if rename_to.name.contains('/') {
+        return error_response(
+            "rename the folder",
+            Box::new(LinksError::BadFolderName(rename_to.name)),
+        );
+    }
+    let cn = String::from(get_user_name(&request)?);
+    info!(
+        "{} renames folder {} to {}",
+        cn, rename_to.path, rename_to.name
+    );
+    change_folders(
+        cn,
+        "rename the folder",
+        StatusCode::OK,
+        move |repo_dir, author| {
+            let parent = rename_to.path.rsplit_once('/').map_or("", |(p, _)| p);
+            let to = join(parent, &rename_to.name);
+            rename(repo_dir, &rename_to.path, &to, author)
+        },
+    )
+    .await
+}
+
+/// `POST /move_folder` with `{"path": "reading", "to": "work"}`, an empty `to` moves it to the top
+pub async fn move_folder(mut request: Request<Body>) -> Result<Response<Body>> {
+    let move_to: MoveFolder = match parse_body(&mut request).await {
+        Ok(move_to) => move_to,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let cn = String::from(get_user_name(&request)?);
+    info!("{} moves folder {} to {:?}", cn, move_to.path, move_to.to);
+    change_folders(
+        cn,
+        "move the folder",
+        StatusCode::OK,
+        move |repo_dir, author| {
+            let name = move_to.path.rsplit('/').next().unwrap_or_default();
+            let to = join(&move_to.to, name);
+            rename(repo_dir, &move_to.path, &to, author)
+        },
+    )
+    .await
+}
+
+/// `POST /move_links` with `{"uuid": "...", "folder": "work"}`
+pub async fn move_links(mut request: Request<Body>) -> Result<Response<Body>> {
+    let move_to: MoveDocument = match parse_body(&mut request).await {
+        Ok(move_to) => move_to,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let cn = String::from(get_user_name(&request)?);
+    if !CONFIG.is_owner(&cn) {
+        return error_response("move the document", Box::new(LinksError::NotOwner(cn)));
+    }
+    info!("{} moves {} to {:?}", cn, move_to.uuid, move_to.folder);
+    // the uuid names a lock, only well formed ones get one
+    if let Err(e) = verify_uuid(&move_to.uuid) {
+        return error_response("move the document", e);
+    }
+    let _guard = locks::lock_document(&move_to.uuid).await;
+    let result = git_worker::run(move || {
+        let user = verify_user(&cn)?;
+        move_document(
+            &CONFIG.storage_dir,
+            &move_to.uuid,
+            &move_to.folder,
+            &CONFIG.signature(user)?,
+        )
+    })
+    .await;
+    saved_response(result, "move the document", StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::test_repo;
+
+    #[test]
+    fn test_folders() {
+        assert!(verify_folder("work/reading list").is_ok());
+        for bad in [
+            "",
+            "/work",
+            "work/",
+            "a/../b",
+            ".git",
+            "trash",
+            "attachments",
+            "work /x",
+        ] {
+            assert!(verify_folder(bad).is_err(), "{}", bad);
+        }
+
+        let uuid = "8b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let dir = test_repo("folders", &format!("{}.md", uuid), "# Doc\n");
+        let author = Signature::now("alice", "_").unwrap();
+        assert!(create(&dir, "work/reading", &author).is_err());
+        create(&dir, "work", &author).unwrap();
+        create(&dir, "work/reading", &author).unwrap();
+        assert!(create(&dir, "work", &author).is_err());
+        create(&dir, "home", &author).unwrap();
+
+        move_document(&dir, uuid, "work/reading", &author).unwrap();
+        let path = format!("work/reading/{}.md", uuid);
+        assert_eq!(find_document(&dir, uuid), Some(path.clone()));
+        assert!(move_document(&dir, uuid, "work/reading", &author).is_err());
+
+        assert!(rename(&dir, "work", "work/reading/work", &author).is_err());
+        assert!(rename(&dir, "work", "home", &author).is_err());
+        rename(&dir, "work/reading", "home/later", &author).unwrap();
+        let path = format!("home/later/{}.md", uuid);
+        assert_eq!(document_path(&dir, uuid).unwrap(), path);
+
+        let tree = read_folder(Path::new(&dir), "", 0).unwrap();
+        assert_eq!(*read_tree(&dir).unwrap(), tree);
+        assert_eq!(tree.folder_paths(), vec!["home", "home/later", "work"]);
+        assert!(tree.documents.is_empty());
+        assert_eq!(tree.folders[0].folders[0].documents[0].uuid, uuid);
+
+        // the commits hold the same structure, and git sees the moves as renames
+        let repo = Repository::open(&dir).unwrap();
+        let head = repo.head().unwrap().peel_to_tree().unwrap();
+        assert_eq!(tree_at(&repo, &head).unwrap(), tree);
+        assert_eq!(find_in_tree(&head, uuid).unwrap().0, path);
+
+        // a failed commit leaves the working tree and the index as they were
+        let branch = repo.head().unwrap().name().unwrap().to_string();
+        let branch_lock = Path::new(&dir).join(format!(".git/{}.lock", branch));
+        fs::write(&branch_lock, "").unwrap();
+        assert!(move_document(&dir, uuid, "home", &author).is_err());
+        fs::remove_file(&branch_lock).unwrap();
+        assert_eq!(find_document(&dir, uuid), Some(path.clone()));
+        let mut index = repo.index().unwrap();
+        index.read(true).unwrap();
+        assert!(index.get_path(Path::new(&path), 0).is_some());
+        assert!(index
+            .get_path(Path::new(&format!("home/{}.md", uuid)), 0)
+            .is_none());
+
+        let blob = repo.blob(b"# Changed\n").unwrap();
+        let changed = repo
+            .find_tree(tree_with_blob(&repo, Some(&head), &path, blob).unwrap())
+            .unwrap();
+        assert_eq!(find_in_tree(&changed, uuid), Some((path, blob)));
+        assert!(changed.get_path(Path::new("work/.folder")).is_ok());
+    }
+}
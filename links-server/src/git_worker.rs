@@ -0,0 +1,119 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+};
+
+use lib_hyper_organizator::typedef::GenericError;
+use tokio::sync::{
+    mpsc::{self, Receiver, Sender},
+    oneshot,
+};
+use tracing::{error, info};
+
+use crate::{router::CONFIG, utils::Result};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The pool used by the request handlers, started on first use
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+/// A few threads dedicated to libgit2 and the blocking file system calls, so a slow commit
+/// or a big history walk never occupies one of the tokio workers serving the other requests.
+pub struct Pool {
+    queue: Sender<Job>,
+}
+
+impl Pool {
+    /// The queue is bounded, once it is full callers wait for a free slot
+    pub fn new(threads: usize, queue_size: usize) -> Pool {
+        let (queue, jobs) = mpsc::channel::<Job>(queue_size.max(1));
+        let jobs = Arc::new(Mutex::new(jobs));
+        for n in 0..threads.max(1) {
+            let jobs = Arc::clone(&jobs);
+            thread::Builder::new()
+                .name(format!("git-worker-{}", n))
+                .spawn(move || work(jobs))
+                .expect("could not start a git worker thread");
+        }
+        Pool { queue }
+    }
+
+    /// Runs the closure on one of the worker threads and waits for its result
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // nobody waiting for the result is not an error of the job
+            let _ = tx.send(f());
+        });
+        if self.queue.send(job).await.is_err() {
+            return Err(GenericError::from("the git workers have stopped"));
+        }
+        rx.await
+            .map_err(|_| GenericError::from("the git worker did not finish the job"))?
+    }
+}
+
+fn work(jobs: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // the lock is only held while waiting, the job runs without it
+        let job = jobs.lock().unwrap().blocking_recv();
+        let Some(job) = job else {
+            return;
+        };
+        // a panicking job drops its result sender, the caller gets an error and the thread lives on
+        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("a job panicked on {:?}", thread::current().name());
+        }
+    }
+}
+
+/// Starts the pool with the configured sizes
+pub fn start() {
+    POOL.get_or_init(|| {
+        info!(
+            "starting {} git workers, queue size {}",
+            CONFIG.git_threads, CONFIG.git_queue_size
+        );
+        Pool::new(CONFIG.git_threads, CONFIG.git_queue_size)
+    });
+}
+
+/// Runs blocking repository work off the async executor
+pub async fn run<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    start();
+    POOL.get().unwrap().run(f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pool() {
+        let pool = Pool::new(2, 1);
+        let name = pool
+            .run(|| Ok(thread::current().name().map(String::from)))
+            .await
+            .unwrap();
+        assert!(name.unwrap().starts_with("git-worker-"));
+
+        let failed: Result<()> = pool.run(|| Err(GenericError::from("boom"))).await;
+        assert_eq!(failed.unwrap_err().to_string(), "boom");
+
+        let panicked: Result<()> = pool.run(|| panic!("boom")).await;
+        assert!(panicked.is_err());
+        // the workers survive a panicking job and more jobs than queue slots still complete
+        let results = futures::future::join_all((0..8).map(|i| pool.run(move || Ok(i * 2)))).await;
+        let results: Vec<i32> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(results, vec![0, 2, 4, 6, 8, 10, 12, 14]);
+    }
+}
@@ -0,0 +1,496 @@
+use git2::{DiffOptions, Oid, Patch, Repository, Sort};
+use hyper::{Body, Request, Response, StatusCode};
+use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
+use serde::Serialize;
+use std::{collections::HashSet, path::Path};
+use tracing::{error, info};
+
+use crate::documents::TRASH_DIR;
+use crate::folders::find_in_tree;
+use crate::git_worker;
+use crate::markdown::extract_links;
+use crate::router::{err, verify_uuid, LinksError, CONFIG};
+use crate::utils::{parse_query, Result};
+
+/// One commit that changed a document
+#[derive(Serialize, Debug)]
+pub struct Revision {
+    pub hash:      String,
+    /// the CN of the certificate used to save
+    pub author:    String,
+    pub email:     String,
+    /// seconds since the epoch
+    pub timestamp: i64,
+    pub message:   String,
+    pub added:     usize,
+    pub removed:   usize,
+}
+
+/// Who last touched a line of a document
+#[derive(Serialize, Debug)]
+pub struct BlameLine {
+    /// 1 based
+    pub line:      usize,
+    pub content:   String,
+    pub hash:      String,
+    pub author:    String,
+    pub email:     String,
+    /// seconds since the epoch
+    pub timestamp: i64,
+}
+
+/// Who added a link, taken from the first line the url appears on
+#[derive(Serialize, Debug)]
+pub struct LinkBlame {
+    pub url:       String,
+    pub text:      String,
+    pub line:      usize,
+    pub hash:      String,
+    pub author:    String,
+    pub email:     String,
+    /// seconds since the epoch
+    pub timestamp: i64,
+}
+
+/// Walks the history from HEAD and keeps the commits that touched the document.
+/// The diffs are limited to the document, so subtrees that did not change are skipped.
+pub fn file_history(repo_dir: &str, uuid: &str) -> std::result::Result<Vec<Revision>, git2::Error> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    // whatever folder it is in, the trash included
+    let mut options = DiffOptions::new();
+    options.pathspec(format!("{}.md", uuid));
+    options.pathspec(format!("*/{}.md", uuid));
+
+    let mut revisions = Vec::new();
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let parent = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let diff =
+            repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), Some(&mut options))?;
+        if diff.deltas().len() == 0 {
+            continue;
+        }
+
+        // a move shows up as a deletion and an addition
+        let (mut previous, mut current) = (None, None);
+        for delta in diff.deltas() {
+            if !delta.old_file().id().is_zero() {
+                previous = Some(delta.old_file().id());
+            }
+            if !delta.new_file().id().is_zero() {
+                current = Some(delta.new_file().id());
+            }
+        }
+        let content = |doc: Option<Oid>| match doc {
+            Some(id) => repo.find_blob(id).map(|b| b.content().to_vec()),
+            None => Ok(Vec::new()),
+        };
+        let (old_content, new_content) = (content(previous)?, content(current)?);
+        let patch = Patch::from_buffers(&old_content, None, &new_content, None, None)?;
+        let (_, added, removed) = patch.line_stats()?;
+
+        let author = commit.author();
+        revisions.push(Revision {
+            hash: commit.id().to_string(),
+            author: author.name().unwrap_or_default().to_string(),
+            email: author.email().unwrap_or_default().to_string(),
+            timestamp: commit.time().seconds(),
+            message: commit.message().unwrap_or_default().trim_end().to_string(),
+            added,
+            removed,
+        });
+    }
+    Ok(revisions)
+}
+
+/// Days since 1970-01-01 for a date in the proleptic Gregorian calendar
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Seconds since the epoch for `YYYY-MM-DD`, `YYYY-MM-DDTHH:MM` or `YYYY-MM-DDTHH:MM:SS`, in UTC.
+/// A date alone means the end of that day, so the last commit of the day is picked.
+fn parse_date(s: &str) -> Option<i64> {
+    let (date, time) = match s.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time.trim_end_matches('Z'))),
+        None => (s, None),
+    };
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    let seconds = match time {
+        Some(time) => {
+            let mut time = time.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+            let (hours, minutes) = (time.next()??, time.next()??);
+            let seconds = time.next().unwrap_or(Some(0))?;
+            if !(0..24).contains(&hours)
+                || !(0..60).contains(&minutes)
+                || !(0..60).contains(&seconds)
+            {
+                return None;
+            }
+            hours * 3600 + minutes * 60 + seconds
+        }
+        None => 86399,
+    };
+    Some(days_from_civil(year, month, day) * 86400 + seconds)
+}
+
+/// The newest commit reachable from HEAD made at or before the given time
+fn commit_before(
+    repo: &Repository,
+    timestamp: i64,
+) -> std::result::Result<Option<Oid>, git2::Error> {
+    let mut walk = repo.revwalk()?;
+    walk.push_head()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    for id in walk {
+        let id = id?;
+        if repo.find_commit(id)?.time().seconds() <= timestamp {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
+
+/// Turns the `at` parameter into a commit: a snapshot name, a date, a number of seconds since
+/// the epoch or anything else git understands as a revision (a hash, `HEAD~2`, a tag)
+pub fn resolve_revision(repo: &Repository, at: &str) -> Result<Oid> {
+    // a snapshot named like a date is still the snapshot
+    let snapshot = repo.find_reference(&format!("refs/tags/{}", at));
+    let id = if let Ok(snapshot) = snapshot {
+        snapshot.peel_to_commit().ok().map(|commit| commit.id())
+    } else if let Some(timestamp) = parse_date(at) {
+        commit_before(repo, timestamp)?
+    } else if let Ok(timestamp) = at.parse::<i64>() {
+        // digits only is a time, even if it also happens to be the start of a hash
+        commit_before(repo, timestamp)?
+    } else if let Ok(object) = repo.revparse_single(at) {
+        // a tree or a blob is no revision either
+        object.peel_to_commit().ok().map(|commit| commit.id())
+    } else {
+        None
+    };
+    match id {
+        Some(id) => Ok(id),
+        None => err!(LinksError::RevisionNotFound(String::from(at))),
+    }
+}
+
+/// Content of a document as of a revision, together with the commit it was resolved to
+pub fn document_at(repo_dir: &str, uuid: &str, at: &str) -> Result<(String, Oid)> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let id = resolve_revision(&repo, at)?;
+    let tree = repo.find_commit(id)?.tree()?;
+    let Some((_, blob)) = find_in_tree(&tree, uuid) else {
+        return err!(LinksError::DocumentNotFound(String::from(uuid)));
+    };
+    let blob = repo.find_blob(blob)?;
+    Ok((String::from_utf8_lossy(blob.content()).into_owned(), id))
+}
+
+/// Content of a document as committed in HEAD, read together with the commit so the pair
+/// always matches, even when a save lands right after. Documents in the trash are not served.
+pub fn head_document(repo_dir: &str, uuid: &str) -> Result<(String, Oid)> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head()?.peel_to_commit()?;
+    let blob = match find_in_tree(&head.tree()?, uuid) {
+        Some((path, blob)) if !path.starts_with(&format!("{}/", TRASH_DIR)) => blob,
+        _ => return err!(LinksError::DocumentNotFound(String::from(uuid))),
+    };
+    let blob = repo.find_blob(blob)?;
+    Ok((
+        String::from_utf8_lossy(blob.content()).into_owned(),
+        head.id(),
+    ))
+}
+
+/// The committed content of a document, line by line, with the commit that last changed each line
+pub fn blame(repo_dir: &str, uuid: &str) -> Result<Vec<BlameLine>> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let tree = repo.head()?.peel_to_tree()?;
+    let Some((path, blob)) = find_in_tree(&tree, uuid) else {
+        return err!(LinksError::DocumentNotFound(String::from(uuid)));
+    };
+    let blob = repo.find_blob(blob)?;
+    let content = String::from_utf8_lossy(blob.content());
+    let blame = repo.blame_file(Path::new(&path), None)?;
+
+    let mut lines = Vec::new();
+    for (i, text) in content.lines().enumerate() {
+        let Some(hunk) = blame.get_line(i + 1) else {
+            continue;
+        };
+        let signature = hunk.final_signature();
+        lines.push(BlameLine {
+            line:      i + 1,
+            content:   text.to_string(),
+            hash:      hunk.final_commit_id().to_string(),
+            author:    signature.name().unwrap_or_default().to_string(),
+            email:     signature.email().unwrap_or_default().to_string(),
+            timestamp: signature.when().seconds(),
+        });
+    }
+    Ok(lines)
+}
+
+/// Blame at the level of links, every url is attributed to the line it first appears on
+pub fn blame_links(lines: Vec<BlameLine>) -> Vec<LinkBlame> {
+    let mut seen = HashSet::new();
+    let mut links = Vec::new();
+    for line in lines {
+        for link in extract_links(&line.content) {
+            if !seen.insert(link.url.clone()) {
+                continue;
+            }
+            links.push(LinkBlame {
+                url:       link.url,
+                text:      link.text,
+                line:      line.line,
+                hash:      line.hash.clone(),
+                author:    line.author.clone(),
+                email:     line.email.clone(),
+                timestamp: line.timestamp,
+            });
+        }
+    }
+    links
+}
+
+/// `/blame?uuid` per line, `/blame?uuid&links` per link url
+pub async fn get_blame(req: Request<Body>) -> Result<Response<Body>> {
+    let (Some(uuid), params) = parse_query(req.uri().query()) else {
+        return "no uuid supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    if verify_uuid(uuid).is_err() {
+        return "bad uuid".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    info!("get_blame: {}", uuid);
+    let owned = String::from(uuid);
+    let mut lines = match git_worker::run(move || blame(&CONFIG.storage_dir, &owned)).await {
+        Ok(lines) => lines,
+        Err(e) if matches!(e.downcast_ref(), Some(LinksError::DocumentNotFound(_))) => {
+            return e
+                .to_string()
+                .to_text_response_with_status(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            error!("Failed to blame {}: {}", uuid, e);
+            return e
+                .to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    for line in lines.iter_mut() {
+        line.author = String::from(CONFIG.cn_of(&line.author, &line.email));
+    }
+    if params.contains_key("links") {
+        serde_json::to_string(&blame_links(lines))?.to_json_response()
+    } else {
+        serde_json::to_string(&lines)?.to_json_response()
+    }
+}
+
+pub async fn get_history(req: Request<Body>) -> Result<Response<Body>> {
+    let (bare, params) = parse_query(req.uri().query());
+    let Some(uuid) = params.get("uuid").map(String::as_str).or(bare) else {
+        return "no uuid supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    if verify_uuid(uuid).is_err() {
+        return "bad uuid".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    }
+    info!("get_history: {}", uuid);
+    let owned = String::from(uuid);
+    match git_worker::run(move || Ok(file_history(&CONFIG.storage_dir, &owned)?)).await {
+        Ok(mut revisions) => {
+            for revision in revisions.iter_mut() {
+                revision.author = String::from(CONFIG.cn_of(&revision.author, &revision.email));
+            }
+            serde_json::to_string(&revisions)?.to_json_response()
+        }
+        Err(e) => {
+            error!("Failed to read the history of {}: {}", uuid, e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::{self, tests::test_repo};
+    use git2::Signature;
+    use std::fs;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-01T00:00:00"), Some(0));
+        assert_eq!(parse_date("1970-01-01"), Some(86399));
+        assert_eq!(parse_date("2024-02-29T12:30"), Some(1709209800));
+        assert_eq!(parse_date("2024-02-29T12:30:15Z"), Some(1709209815));
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("2023-02-29"), None);
+        assert_eq!(parse_date("2024-04-31"), None);
+        assert_eq!(parse_date("2024-02-29T24:00"), None);
+        assert_eq!(parse_date("2024-02-29T12:60"), None);
+        assert_eq!(parse_date("2024-02-29T12:30:60"), None);
+        assert_eq!(parse_date("1709209800"), None);
+        assert_eq!(parse_date("a1b2c3d"), None);
+    }
+
+    #[test]
+    fn test_document_at() {
+        let uuid = "0c4b3e1a-2f6d-4e8a-9b1c-5d7e9f0a1b2c";
+        let file_name = format!("{}.md", uuid);
+        let dir = test_repo("document-at", &file_name, "first\n");
+        let first = save_to_git::head_revision(&dir).unwrap();
+        fs::write(Path::new(&dir).join(&file_name), "second\n").unwrap();
+        save_to_git::commit(
+            &dir,
+            &[&file_name],
+            &Signature::now("alice", "_").unwrap(),
+            "saved via gui",
+        )
+        .unwrap();
+
+        let (content, id) = document_at(&dir, uuid, &first).unwrap();
+        assert_eq!((content.as_str(), id.to_string()), ("first\n", first));
+        let (content, _) = document_at(&dir, uuid, "HEAD").unwrap();
+        assert_eq!(content, "second\n");
+        let (content, _) = document_at(&dir, uuid, "2999-01-01").unwrap();
+        assert_eq!(content, "second\n");
+        let (content, _) = document_at(&dir, uuid, "32503680000").unwrap();
+        assert_eq!(content, "second\n");
+        // digits are a time, never the start of a hash
+        let e = document_at(&dir, uuid, "1000000").unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&LinksError::RevisionNotFound(String::from("1000000")))
+        );
+        let e = document_at(&dir, uuid, "1999-01-01").unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&LinksError::RevisionNotFound(String::from("1999-01-01")))
+        );
+        for at in ["garbage", "HEAD^{tree}", "HEAD~5"] {
+            let e = document_at(&dir, uuid, at).unwrap_err();
+            assert_eq!(
+                e.downcast_ref(),
+                Some(&LinksError::RevisionNotFound(String::from(at)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_blame() {
+        let uuid = "7d1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let file_name = format!("{}.md", uuid);
+        let dir = test_repo("blame", &file_name, "# Title\n- [a](http://a)\n");
+        fs::write(
+            Path::new(&dir).join(&file_name),
+            "# Title\n- [a](http://a)\n- [b](http://b) and [a again](http://a)\n",
+        )
+        .unwrap();
+        save_to_git::commit(
+            &dir,
+            &[&file_name],
+            &Signature::now("alice", "_").unwrap(),
+            "saved via gui",
+        )
+        .unwrap();
+
+        let lines = blame(&dir, uuid).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].author, "test");
+        assert_eq!(lines[2].author, "alice");
+        assert_eq!(lines[2].content, "- [b](http://b) and [a again](http://a)");
+
+        let links = blame_links(lines);
+        assert_eq!(links.len(), 2);
+        assert_eq!(
+            (links[0].url.as_str(), links[0].author.as_str()),
+            ("http://a", "test")
+        );
+        assert_eq!(
+            (links[1].url.as_str(), links[1].author.as_str()),
+            ("http://b", "alice")
+        );
+    }
+
+    #[test]
+    fn test_file_history() {
+        let uuid = "329f4aef-f624-4ed1-8a89-bb9bb356a66a";
+        let file_name = format!("{}.md", uuid);
+        let dir = test_repo("history", &file_name, "# Title\n- [a](http://a)\n");
+        fs::write(
+            Path::new(&dir).join(&file_name),
+            "# Title\n- [b](http://b)\n- [c](http://c)\n",
+        )
+        .unwrap();
+        save_to_git::commit(
+            &dir,
+            &[&file_name],
+            &Signature::now("alice", "_").unwrap(),
+            "saved via gui",
+        )
+        .unwrap();
+        // a commit that does not touch the document
+        fs::write(Path::new(&dir).join("other.md"), "# Other\n").unwrap();
+        save_to_git::commit(
+            &dir,
+            &["other.md"],
+            &Signature::now("bob", "_").unwrap(),
+            "saved via gui",
+        )
+        .unwrap();
+
+        let history = file_history(&dir, uuid).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].author, "alice");
+        assert_eq!((history[0].added, history[0].removed), (2, 1));
+        assert_eq!(history[1].message, "initial");
+        assert_eq!((history[1].added, history[1].removed), (2, 0));
+
+        // moving the document into a folder keeps its history
+        fs::create_dir(Path::new(&dir).join("work")).unwrap();
+        let moved = format!("work/{}", file_name);
+        fs::rename(
+            Path::new(&dir).join(&file_name),
+            Path::new(&dir).join(&moved),
+        )
+        .unwrap();
+        save_to_git::commit(
+            &dir,
+            &[&file_name, &moved],
+            &Signature::now("bob", "_").unwrap(),
+            "moved",
+        )
+        .unwrap();
+        let history = file_history(&dir, uuid).unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!((history[0].added, history[0].removed), (0, 0));
+        assert_eq!(history[1].author, "alice");
+    }
+}
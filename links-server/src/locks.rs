@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex, MutexGuard, Weak},
+    time::Instant,
+};
+
+use async_lock::{Mutex, MutexGuardArc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use lazy_static::lazy_static;
+use tracing::{field::Empty, info_span, Instrument, Span};
+
+lazy_static! {
+    /// One lock per document, only kept while somebody holds or waits for it
+    static ref DOCUMENTS: StdMutex<HashMap<String, Weak<Mutex<()>>>> = StdMutex::new(HashMap::new());
+    /// Document writes share the working tree, a sync rewrites all of it and takes it exclusively
+    static ref WORKING_TREE: RwLock<()> = RwLock::new(());
+}
+
+/// Staging and committing touch the index and HEAD, shared by all documents
+static REPOSITORY: StdMutex<()> = StdMutex::new(());
+
+/// Held while a document is read, written and committed
+pub struct DocumentGuard {
+    _document: MutexGuardArc<()>,
+    _tree:     RwLockReadGuard<'static, ()>,
+}
+
+fn document_lock(uuid: &str) -> Arc<Mutex<()>> {
+    let mut documents = DOCUMENTS.lock().unwrap();
+    if let Some(lock) = documents.get(uuid).and_then(Weak::upgrade) {
+        return lock;
+    }
+    // forget the documents nobody is working on any more
+    documents.retain(|_, lock| lock.strong_count() > 0);
+    let lock = Arc::new(Mutex::new(()));
+    documents.insert(String::from(uuid), Arc::downgrade(&lock));
+    lock
+}
+
+fn record_wait(span: &Span, start: Instant) {
+    span.record("wait_ms", start.elapsed().as_millis() as u64);
+}
+
+/// Serialises the writes to one document, writes to other documents go ahead in parallel
+pub async fn lock_document(uuid: &str) -> DocumentGuard {
+    let span = info_span!("document_lock", uuid, wait_ms = Empty);
+    let start = Instant::now();
+    let lock = document_lock(uuid);
+    let guard = async {
+        let tree = WORKING_TREE.read().await;
+        DocumentGuard {
+            _document: lock.lock_arc().await,
+            _tree:     tree,
+        }
+    }
+    .instrument(span.clone())
+    .await;
+    record_wait(&span, start);
+    guard
+}
+
+/// Waits for all the document writes to finish and keeps new ones out
+pub async fn lock_working_tree() -> RwLockWriteGuard<'static, ()> {
+    let span = info_span!("working_tree_lock", wait_ms = Empty);
+    let start = Instant::now();
+    let guard = WORKING_TREE.write().instrument(span.clone()).await;
+    record_wait(&span, start);
+    guard
+}
+
+/// Keeps the syncs out while the working tree is read as a whole, document writes go on
+pub async fn read_working_tree() -> RwLockReadGuard<'static, ()> {
+    let span = info_span!("working_tree_read_lock", wait_ms = Empty);
+    let start = Instant::now();
+    let guard = WORKING_TREE.read().instrument(span.clone()).await;
+    record_wait(&span, start);
+    guard
+}
+
+/// Short lock around the index and commit, taken from the git worker threads
+pub fn lock_repository() -> MutexGuard<'static, ()> {
+    let span = info_span!("repository_lock", wait_ms = Empty);
+    let start = Instant::now();
+    let guard = span.in_scope(|| REPOSITORY.lock().unwrap_or_else(|e| e.into_inner()));
+    record_wait(&span, start);
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_document_locks() {
+        let a = lock_document("a").await;
+        // another document is not held up
+        let b = timeout(Duration::from_millis(100), lock_document("b")).await;
+        assert!(b.is_ok());
+        // the same document waits
+        let again = timeout(Duration::from_millis(100), lock_document("a")).await;
+        assert!(again.is_err());
+        drop(a);
+        let again = timeout(Duration::from_millis(100), lock_document("a")).await;
+        assert!(again.is_ok());
+        drop(again);
+        drop(b);
+
+        // the working tree lock waits for the documents and the documents wait for it
+        let document = lock_document("c").await;
+        assert!(timeout(Duration::from_millis(100), lock_working_tree())
+            .await
+            .is_err());
+        drop(document);
+        let tree = lock_working_tree().await;
+        assert!(timeout(Duration::from_millis(100), lock_document("c"))
+            .await
+            .is_err());
+        drop(tree);
+
+        // reading the whole tree goes along with the documents but keeps the syncs out
+        let document = lock_document("c").await;
+        let reading = read_working_tree().await;
+        assert!(timeout(Duration::from_millis(100), lock_working_tree())
+            .await
+            .is_err());
+        drop(document);
+        drop(reading);
+
+        // taking a new lock forgets the documents nobody holds any more
+        let d = lock_document("d").await;
+        assert!(DOCUMENTS.lock().unwrap().contains_key("d"));
+        assert!(!DOCUMENTS.lock().unwrap().contains_key("c"));
+        drop(d);
+        let e = lock_document("e").await;
+        assert!(!DOCUMENTS.lock().unwrap().contains_key("d"));
+        drop(e);
+    }
+}
@@ -1,9 +1,26 @@
+mod archive;
+mod attachments;
+mod backup;
+mod bookmarks;
 mod catalog;
 mod circular_string;
+mod diff;
+mod documents;
+mod drafts;
+mod folders;
+mod git_worker;
+mod history;
 mod links;
+mod locks;
+mod markdown;
+mod opml;
+mod proposals;
+mod push;
 mod router;
 mod save_to_git;
+mod snapshots;
 mod static_files;
+mod sync;
 mod utils;
 
 /*
@@ -19,7 +36,26 @@ static GLOBAL: MiMalloc = MiMalloc;
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::fmt::init();
+    save_to_git::init(&router::CONFIG.storage_dir)?;
+    git_worker::start();
+    push::start();
+    sync::start();
     lazy_static::initialize(&links::CLICK_LOG);
-    lib_hyper_organizator::server::start_servers(router::request_handler, None).await?;
+    tokio::select! {
+        result = lib_hyper_organizator::server::start_servers(router::request_handler, None) => result?,
+        _ = shutdown_signal() => {
+            tracing::info!("shutting down, pushing the last commits");
+            push::flush().await;
+        }
+    }
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
@@ -0,0 +1,125 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// A markdown link, `[text](url)`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Link {
+    pub text: String,
+    pub url:  String,
+}
+
+/// All the inline links in a markdown document, in the order they appear. Images are not links.
+pub fn extract_links(content: &str) -> Vec<Link> {
+    lazy_static! {
+        // no look-behind, the `!` of an image is matched and then left out
+        static ref LINK: Regex =
+            Regex::new(r#"(!?)\[([^\]]*)\]\(\s*<?([^)\s>]+)>?(?:\s+"[^"]*")?\s*\)"#).unwrap();
+    }
+    LINK.captures_iter(content)
+        .filter(|c| c[1].is_empty())
+        .map(|c| Link {
+            text: c[2].trim().to_string(),
+            url:  c[3].to_string(),
+        })
+        .collect()
+}
+
+/// A heading, `## Text`, as its level and its text
+pub fn heading(line: &str) -> Option<(usize, &str)> {
+    lazy_static! {
+        static ref HEADING: Regex = Regex::new(r#"^(#{1,6})\s+(.*)$"#).unwrap();
+    }
+    let heading = HEADING.captures(line.trim_end())?;
+    Some((heading.get(1)?.as_str().len(), heading.get(2)?.as_str()))
+}
+
+/// Starts a section, a heading at `level` that is at most six deep
+pub fn push_heading(markdown: &mut String, level: usize, text: &str) {
+    if !markdown.is_empty() {
+        markdown.push('\n');
+    }
+    markdown.push_str(&format!("{} {}\n\n", "#".repeat(level.min(6)), text));
+}
+
+/// The items of a section in the order they are written, the leaves before the subsections.
+/// Markdown has no end to a heading section, a leaf written after a subsection would be read
+/// back inside it.
+pub fn leaves_first<T>(items: &[T], is_leaf: impl Fn(&T) -> bool) -> impl Iterator<Item = &T> {
+    let (leaves, sections): (Vec<&T>, Vec<&T>) = items.iter().partition(|item| is_leaf(item));
+    leaves.into_iter().chain(sections)
+}
+
+/// The sections of a document while it is read. A heading nests what follows it up to the next
+/// heading of the same level or above, the document itself is the outermost section at level 0.
+pub struct Sections<T> {
+    open:  Vec<(usize, T)>,
+    /// puts a section that ended into its parent
+    close: fn(T, &mut T),
+}
+
+impl<T> Sections<T> {
+    pub fn new(document: T, close: fn(T, &mut T)) -> Sections<T> {
+        Sections {
+            open: vec![(0, document)],
+            close,
+        }
+    }
+
+    /// Starts the section of a heading, those it ends are closed first
+    pub fn open(&mut self, level: usize, section: T) {
+        while self.open.len() > 1 && self.open.last().is_some_and(|(l, _)| *l >= level) {
+            self.close_last();
+        }
+        self.open.push((level, section));
+    }
+
+    /// The section the line being read belongs to
+    pub fn current(&mut self) -> &mut T {
+        let last = self.open.len() - 1;
+        &mut self.open[last].1
+    }
+
+    fn close_last(&mut self) {
+        if let Some((_, section)) = self.open.pop() {
+            if let Some((_, parent)) = self.open.last_mut() {
+                (self.close)(section, parent);
+            }
+        }
+    }
+
+    /// The document, with every section closed
+    pub fn finish(mut self) -> T {
+        while self.open.len() > 1 {
+            self.close_last();
+        }
+        self.open.swap_remove(0).1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_links() {
+        let content = "# Title\n- [Rust](https://www.rust-lang.org) and [docs]( <https://docs.rs> \"Docs\")\n- ![img](/a.png)![b](/b.png)[next](/next)\n[empty]()\n";
+        let links = extract_links(content);
+        assert_eq!(
+            links,
+            vec![
+                Link {
+                    text: String::from("Rust"),
+                    url:  String::from("https://www.rust-lang.org"),
+                },
+                Link {
+                    text: String::from("docs"),
+                    url:  String::from("https://docs.rs"),
+                },
+                Link {
+                    text: String::from("next"),
+                    url:  String::from("/next"),
+                },
+            ]
+        );
+    }
+}
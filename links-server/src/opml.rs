@@ -0,0 +1,377 @@
+use std::{fs, path::Path};
+
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use lib_hyper_organizator::response_utils::{read_full_body, IntoResultHyperResponse};
+use regex::Regex;
+use tracing::{error, info};
+
+use crate::{
+    bookmarks::{encode_entities, looks_encrypted, markdown_link},
+    catalog::trim,
+    diff::title_of,
+    documents::{self, NewDocument},
+    folders, git_worker,
+    markdown::{extract_links, heading, leaves_first, push_heading, Sections},
+    router::{err, verify_uuid, LinksError, CONFIG},
+    utils::{get_user_name, parse_query, Result},
+};
+
+/// The text of the link to a feed, next to the link to the page
+const FEED: &str = "feed";
+
+/// Title for imported lists whose head has none
+const UNTITLED: &str = "Imported";
+
+/// An OPML outline. Those with children are headings in markdown, the others list items.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct Outline {
+    pub text:     String,
+    pub html_url: Option<String>,
+    pub xml_url:  Option<String>,
+    pub children: Vec<Outline>,
+}
+
+fn read_outline(node: roxmltree::Node) -> Outline {
+    let attribute = |name: &str| {
+        node.attribute(name)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+    };
+    let html_url = attribute("htmlUrl").or_else(|| attribute("url"));
+    let xml_url = attribute("xmlUrl");
+    Outline {
+        text: attribute("text")
+            .or_else(|| attribute("title"))
+            .or_else(|| html_url.clone())
+            .or_else(|| xml_url.clone())
+            .unwrap_or_default(),
+        children: node
+            .children()
+            .filter(|child| child.has_tag_name("outline"))
+            .map(read_outline)
+            .collect(),
+        html_url,
+        xml_url,
+    }
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+/// The title in the head and the outlines of the body
+pub fn parse_opml(xml: &str) -> Result<(Option<String>, Vec<Outline>)> {
+    let document = match roxmltree::Document::parse(xml) {
+        Ok(document) => document,
+        Err(e) => return err!(LinksError::BadOpml(e.to_string())),
+    };
+    let opml = document.root_element();
+    if !opml.has_tag_name("opml") {
+        return err!(LinksError::BadOpml(String::from("the root is not <opml>")));
+    }
+    let title = child(opml, "head")
+        .and_then(|head| child(head, "title"))
+        .and_then(|title| title.text())
+        .map(|title| title.trim().to_string())
+        .filter(|title| !title.is_empty());
+    let Some(body) = child(opml, "body") else {
+        return err!(LinksError::BadOpml(String::from("no <body>")));
+    };
+    let outlines = body
+        .children()
+        .filter(|child| child.has_tag_name("outline"))
+        .map(read_outline)
+        .collect();
+    Ok((title, outlines))
+}
+
+/// The text with the links of the outline, for a list item or a heading
+fn outline_text(outline: &Outline) -> String {
+    let text = outline.text.replace('[', "(").replace(']', ")");
+    let mut item = match &outline.html_url {
+        Some(url) => markdown_link(&text, url),
+        None => text,
+    };
+    if let Some(url) = &outline.xml_url {
+        item.push(' ');
+        item.push_str(&markdown_link(FEED, url));
+    }
+    item
+}
+
+/// Outlines with children become headings starting at `level`, with their links like the list
+/// items. The leaves go first.
+pub fn to_markdown(outlines: &[Outline], level: usize) -> String {
+    let mut markdown = String::new();
+    for outline in leaves_first(outlines, |outline| outline.children.is_empty()) {
+        if outline.children.is_empty() {
+            markdown.push_str(&format!("- {}\n", outline_text(outline)));
+            continue;
+        }
+        push_heading(&mut markdown, level, &outline_text(outline));
+        markdown.push_str(&to_markdown(&outline.children, level + 1));
+    }
+    markdown
+}
+
+fn parse_item(item: &str) -> Outline {
+    lazy_static! {
+        static ref FEED_LINK: Regex = Regex::new(r#"\[feed\]\([^)]*\)"#).unwrap();
+    }
+    let links = extract_links(item);
+    let page = links.iter().find(|link| link.text != FEED);
+    Outline {
+        text:     match page {
+            Some(link) => link.text.clone(),
+            None => FEED_LINK.replace_all(item, "").trim().to_string(),
+        },
+        html_url: page.map(|link| link.url.clone()),
+        xml_url:  links
+            .iter()
+            .find(|link| link.text == FEED)
+            .map(|link| link.url.clone()),
+        children: Vec::new(),
+    }
+}
+
+/// The reverse of `to_markdown`: headings nest the list items that follow them.
+/// The first line is the title of the document and is left out.
+pub fn from_markdown(content: &str) -> Vec<Outline> {
+    lazy_static! {
+        static ref ITEM: Regex = Regex::new(r#"^\s*[-*+]\s+(.*)$"#).unwrap();
+    }
+    let mut sections = Sections::new(Outline::default(), |outline, parent: &mut Outline| {
+        parent.children.push(outline)
+    });
+    for line in content.lines().skip(1) {
+        let line = line.trim_end();
+        if let Some((level, text)) = heading(line) {
+            sections.open(level, parse_item(trim(text)));
+        } else if let Some(item) = ITEM.captures(line) {
+            sections.current().children.push(parse_item(&item[1]));
+        } else {
+            // links in running text still belong in the list
+            for link in extract_links(line) {
+                sections.current().children.push(Outline {
+                    text: link.text,
+                    html_url: Some(link.url),
+                    ..Outline::default()
+                });
+            }
+        }
+    }
+    sections.finish().children
+}
+
+fn write_outlines(xml: &mut String, outlines: &[Outline], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for outline in outlines {
+        xml.push_str(&format!(
+            r#"{}<outline text="{}""#,
+            indent,
+            encode_entities(&outline.text)
+        ));
+        if outline.xml_url.is_some() {
+            xml.push_str(r#" type="rss""#);
+        }
+        if let Some(url) = &outline.xml_url {
+            xml.push_str(&format!(r#" xmlUrl="{}""#, encode_entities(url)));
+        }
+        if let Some(url) = &outline.html_url {
+            xml.push_str(&format!(r#" htmlUrl="{}""#, encode_entities(url)));
+        }
+        if outline.children.is_empty() {
+            xml.push_str("/>\n");
+        } else {
+            xml.push_str(">\n");
+            write_outlines(xml, &outline.children, depth + 1);
+            xml.push_str(&format!("{}</outline>\n", indent));
+        }
+    }
+}
+
+pub fn to_opml(title: &str, outlines: &[Outline]) -> String {
+    let mut xml = format!(
+        indoc::indoc! {r#"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <opml version="2.0">
+              <head>
+                <title>{}</title>
+              </head>
+              <body>
+        "#},
+        encode_entities(title)
+    );
+    write_outlines(&mut xml, outlines, 2);
+    xml.push_str("  </body>\n</opml>\n");
+    xml
+}
+
+fn export(uuid: &str) -> Result<String> {
+    let path = folders::document_path(&CONFIG.storage_dir, uuid)?;
+    let content = fs::read_to_string(Path::new(&CONFIG.storage_dir).join(path))?;
+    if looks_encrypted(&content) {
+        return err!(LinksError::DocumentEncrypted(String::from(uuid)));
+    }
+    Ok(to_opml(title_of(uuid, &content), &from_markdown(&content)))
+}
+
+fn error_response(
+    what: &str,
+    e: lib_hyper_organizator::typedef::GenericError,
+) -> Result<Response<Body>> {
+    match e.downcast_ref() {
+        Some(LinksError::BadUuid(_)) | Some(LinksError::BadOpml(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::BAD_REQUEST),
+        Some(LinksError::DocumentNotFound(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Some(LinksError::NotOwner(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        Some(LinksError::DocumentEncrypted(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::UNPROCESSABLE_ENTITY),
+        _ => {
+            error!("Failed to {}: {}", what, e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// `/export_opml?uuid`
+pub async fn export_opml(req: Request<Body>) -> Result<Response<Body>> {
+    let (Some(uuid), _) = parse_query(req.uri().query()) else {
+        return "no document supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let name = String::from(uuid);
+    match git_worker::run(move || export(&name)).await {
+        Ok(opml) => Ok(Response::builder()
+            .header("Content-Type", "text/x-opml; charset=UTF-8")
+            .header(
+                "Content-Disposition",
+                format!(r#"attachment; filename="{}.opml""#, uuid),
+            )
+            .body(Body::from(opml))?),
+        Err(e) => error_response("export the OPML", e),
+    }
+}
+
+/// `POST /import_opml` creates a document named after the title in the head,
+/// `POST /import_opml?uuid` appends the outlines to an existing document
+pub async fn import_opml(mut request: Request<Body>) -> Result<Response<Body>> {
+    let whole_body = read_full_body(&mut request).await?;
+    let (title, outlines) = match parse_opml(&String::from_utf8_lossy(&whole_body)) {
+        Ok(opml) => opml,
+        Err(e) => return error_response("import the OPML", e),
+    };
+    let user = get_user_name(&request)?;
+    let (target, _) = parse_query(request.uri().query());
+    info!(
+        "{} imports {} outlines into {:?}",
+        user,
+        outlines.len(),
+        target
+    );
+
+    let markdown = to_markdown(&outlines, 2);
+    let result = match target {
+        Some(uuid) => {
+            if let Err(e) = verify_uuid(uuid) {
+                return error_response("import the OPML", e);
+            }
+            let message = format!("imported {}", title.as_deref().unwrap_or("OPML"));
+            documents::append(uuid, markdown, user, message)
+                .await
+                .and_then(|saved| Ok(serde_json::to_string(&saved)?))
+        }
+        None => {
+            let document = NewDocument {
+                title:   Some(title.unwrap_or_else(|| String::from(UNTITLED))),
+                content: Some(markdown),
+                folder:  None,
+            };
+            documents::create(document, user)
+                .await
+                .and_then(|created| Ok(serde_json::to_string(&created)?))
+        }
+    };
+    match result {
+        Ok(json) => Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))?),
+        Err(e) => error_response("import the OPML", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="2.0">
+  <head><title>Reading &amp; feeds</title></head>
+  <body>
+    <outline text="Rust" title="ignored" htmlUrl="https://www.rust-lang.org/">
+      <outline text="This Week in Rust" type="rss" xmlUrl="https://this-week-in-rust.org/rss.xml" htmlUrl="https://this-week-in-rust.org/"/>
+      <outline text="Blogs">
+        <outline text="Only a feed" xmlUrl="https://example.com/atom.xml"/>
+        <outline title="A page [draft]" url="https://example.com/(x)"/>
+      </outline>
+    </outline>
+    <outline text="A note"/>
+  </body>
+</opml>
+"#;
+
+    #[test]
+    fn test_opml_round_trip() {
+        let (title, outlines) = parse_opml(OPML).unwrap();
+        assert_eq!(title.as_deref(), Some("Reading & feeds"));
+        assert_eq!(outlines.len(), 2);
+        let markdown = to_markdown(&outlines, 2);
+        assert_eq!(
+            markdown,
+            "- A note\n\n\
+            ## [Rust](https://www.rust-lang.org/)\n\n\
+            - [This Week in Rust](https://this-week-in-rust.org/) [feed](https://this-week-in-rust.org/rss.xml)\n\n\
+            ### Blogs\n\n\
+            - Only a feed [feed](https://example.com/atom.xml)\n\
+            - [A page (draft)](https://example.com/%28x%29)\n"
+        );
+
+        // what the document looks like once created, then back to OPML and markdown again
+        let content = format!("# {}\n\n{}", title.unwrap(), markdown);
+        let exported = to_opml(title_of("uuid", &content), &from_markdown(&content));
+        assert!(exported.contains("<title>Reading &amp; feeds</title>"));
+        let (title, again) = parse_opml(&exported).unwrap();
+        assert_eq!(title.as_deref(), Some("Reading & feeds"));
+        assert_eq!(to_markdown(&again, 2), markdown);
+        assert_eq!(again.len(), 2);
+        assert_eq!(again[0].text, "A note");
+        assert!(again[0].children.is_empty());
+        assert_eq!(again[1].text, "Rust");
+        assert_eq!(
+            again[1].html_url.as_deref(),
+            Some("https://www.rust-lang.org/")
+        );
+        let blogs = &again[1].children[1];
+        assert_eq!(blogs.text, "Blogs");
+        assert_eq!(blogs.children.len(), 2);
+        assert_eq!(
+            again[1].children[0].xml_url.as_deref(),
+            Some("https://this-week-in-rust.org/rss.xml")
+        );
+
+        assert!(parse_opml("<rss/>").is_err());
+        assert!(parse_opml("not xml").is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use std::{fs, path::Path};
+
+use git2::{Commit, Oid, Repository, Signature};
+use hyper::{Body, Request, Response, StatusCode};
+use lib_hyper_organizator::response_utils::{parse_body, IntoResultHyperResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    diff::{commit_summary, link_diff, title_of, unified_diff, LinkDiff},
+    documents::TRASH_DIR,
+    folders::{document_path, find_in_tree, tree_with_blob},
+    git_worker, locks,
+    router::{err, verify_user, verify_uuid, LinksError, Payload, SaveResponse, CONFIG},
+    save_to_git::{self, MergeResult},
+    utils::{get_user_name, Result},
+};
+
+/// One branch per user and document, next to the normal branches so they travel with the repository
+pub(crate) const PROPOSALS: &str = "refs/heads/proposals/";
+
+/// Changes a user without write permission wants to make to a document
+#[derive(Serialize, Debug)]
+pub struct Proposal {
+    /// `cn/uuid`, used to accept or reject the proposal
+    pub id:        String,
+    pub author:    String,
+    pub uuid:      String,
+    pub title:     String,
+    /// the last commit of the proposal
+    pub revision:  String,
+    /// where the proposal forked off the history
+    pub base:      String,
+    /// seconds since the epoch, of the last commit
+    pub timestamp: i64,
+    pub message:   String,
+    /// unified diff from the base
+    pub diff:      String,
+    pub links:     LinkDiff,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProposalId {
+    id: String,
+}
+
+fn ref_name(cn: &str, uuid: &str) -> String {
+    format!("{}{}/{}", PROPOSALS, cn, uuid)
+}
+
+/// Splits a proposal id into the user and the document
+fn parse_id(id: &str) -> Result<(&str, &str)> {
+    let Some((cn, uuid)) = id.split_once('/') else {
+        return err!(LinksError::ProposalNotFound(String::from(id)));
+    };
+    verify_user(cn)?;
+    verify_uuid(uuid)?;
+    Ok((cn, uuid))
+}
+
+/// Content of a document in a commit, whatever folder it is in, empty if it is not there
+fn content_in(repo: &Repository, commit: &Commit, uuid: &str) -> Result<String> {
+    match find_in_tree(&commit.tree()?, uuid) {
+        Some((_, id)) => {
+            let blob = repo.find_blob(id)?;
+            Ok(String::from_utf8_lossy(blob.content()).into_owned())
+        }
+        None => Ok(String::new()),
+    }
+}
+
+/// Commits the content to the proposal branch of the user, the working tree and HEAD are not touched.
+/// The first save forks off `base`, the revision the editor loaded, so the commits made since are
+/// not undone when it is accepted. The following saves stack on top of the branch, merged when
+/// the editor loaded something older. Returns the merged content when it differs from `content`.
+pub fn propose(
+    repo_dir: &str,
+    cn: &str,
+    uuid: &str,
+    base: Option<&str>,
+    content: &str,
+    author: &Signature,
+    message: Option<&str>,
+) -> Result<(Oid, Option<String>)> {
+    verify_user(cn)?;
+    verify_uuid(uuid)?;
+    let _guard = locks::lock_repository();
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head()?.peel_to_commit()?;
+    let file_name = match find_in_tree(&head.tree()?, uuid) {
+        Some((path, _)) if !path.starts_with(&format!("{}/", TRASH_DIR)) => path,
+        _ => return err!(LinksError::DocumentNotFound(String::from(uuid))),
+    };
+    // without a base the content goes in as it is, like a save without a revision
+    let base = match base {
+        Some(base) => match repo.revparse_single(base).and_then(|b| b.peel_to_commit()) {
+            Ok(base) => Some(base),
+            Err(_) => return err!(LinksError::RevisionNotFound(String::from(base))),
+        },
+        None => None,
+    };
+    let branch = ref_name(cn, uuid);
+    let parent = match (repo.find_reference(&branch), &base) {
+        (Ok(reference), _) => reference.peel_to_commit()?,
+        (Err(_), Some(base)) => base.clone(),
+        (Err(_), None) => head,
+    };
+    let parent_tree = parent.tree()?;
+    // stay where the branch has the document, even if it was moved since
+    let file_name = find_in_tree(&parent_tree, uuid).map_or(file_name, |(path, _)| path);
+
+    let old = content_in(&repo, &parent, uuid)?;
+    let merged = match &base {
+        Some(base) if base.id() != parent.id() => {
+            let base = base.id().to_string();
+            match save_to_git::merge(repo_dir, &file_name, &base, &old, content)? {
+                MergeResult::Clean(merged) => Some(merged).filter(|merged| merged != content),
+                MergeResult::Conflict(hunks) => return err!(LinksError::MergeConflict(hunks)),
+            }
+        }
+        _ => None,
+    };
+    let content = merged.as_deref().unwrap_or(content);
+    if old == content {
+        return err!(LinksError::ContentNotChanged);
+    }
+    let blob = repo.blob(content.as_bytes())?;
+    let tree = repo.find_tree(tree_with_blob(&repo, Some(&parent_tree), &file_name, blob)?)?;
+    let message = match message.map(str::trim) {
+        Some(message) if !message.is_empty() => String::from(message),
+        _ => commit_summary(uuid, &old, content),
+    };
+    let id = repo.commit(Some(&branch), author, author, &message, &tree, &[&parent])?;
+    info!("{} proposed {} for {}", cn, id, uuid);
+    Ok((id, merged))
+}
+
+/// Where `save_links` goes for users who are not owners
+pub(crate) fn propose_document(p: Payload, cn: &str) -> Result<SaveResponse> {
+    let (id, merged) = propose(
+        &CONFIG.storage_dir,
+        cn,
+        &p.uuid,
+        p.revision.as_deref(),
+        &p.content,
+        &CONFIG.signature(cn)?,
+        p.message.as_deref(),
+    )?;
+    Ok(SaveResponse {
+        revision: id.to_string(),
+        merged,
+        proposal: Some(format!("{}/{}", cn, p.uuid)),
+    })
+}
+
+/// All the open proposals, with what they change
+pub fn proposals(repo_dir: &str) -> Result<Vec<Proposal>> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head()?.peel_to_commit()?;
+    let mut proposals = Vec::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let Some(id) = reference.name().and_then(|n| n.strip_prefix(PROPOSALS)) else {
+            continue;
+        };
+        let Ok((cn, uuid)) = parse_id(id) else {
+            continue;
+        };
+        let tip = reference.peel_to_commit()?;
+        let base = repo.find_commit(repo.merge_base(head.id(), tip.id())?)?;
+        let file_name = find_in_tree(&tip.tree()?, uuid)
+            .map_or_else(|| format!("{}.md", uuid), |(path, _)| path);
+        let old = content_in(&repo, &base, uuid)?;
+        let new = content_in(&repo, &tip, uuid)?;
+        proposals.push(Proposal {
+            id:        String::from(id),
+            author:    String::from(cn),
+            uuid:      String::from(uuid),
+            title:     title_of(uuid, &new).to_string(),
+            revision:  tip.id().to_string(),
+            base:      base.id().to_string(),
+            timestamp: tip.time().seconds(),
+            message:   tip.message().unwrap_or_default().to_string(),
+            diff:      unified_diff(&file_name, &old, &new)?,
+            links:     link_diff(&old, &new),
+        });
+    }
+    proposals.sort_by_key(|p| std::cmp::Reverse(p.timestamp));
+    Ok(proposals)
+}
+
+/// Merges the proposal into the document, commits it as the proposer and drops the branch.
+/// Returns `None` when the document already had all the proposed changes.
+pub fn accept(repo_dir: &str, id: &str, author: &Signature, owner: &str) -> Result<Option<Oid>> {
+    let (cn, uuid) = parse_id(id)?;
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let Ok(mut reference) = repo.find_reference(&ref_name(cn, uuid)) else {
+        return err!(LinksError::ProposalNotFound(String::from(id)));
+    };
+    let tip = reference.peel_to_commit()?;
+    let head = repo.head()?.peel_to_commit()?;
+    let base = repo.merge_base(head.id(), tip.id())?;
+    let file_name = document_path(repo_dir, uuid)?;
+    let current = fs::read_to_string(Path::new(repo_dir).join(&file_name))?;
+    let proposed = content_in(&repo, &tip, uuid)?;
+
+    let merged =
+        match save_to_git::merge(repo_dir, &file_name, &base.to_string(), &current, &proposed)? {
+            MergeResult::Clean(merged) => merged,
+            MergeResult::Conflict(hunks) => return err!(LinksError::MergeConflict(hunks)),
+        };
+    let id = if merged == current {
+        None
+    } else {
+        let message = format!(
+            "{}\n\nproposed by {} in {}, accepted by {}",
+            commit_summary(uuid, &current, &merged).trim_end(),
+            cn,
+            tip.id(),
+            owner
+        );
+        Some(save_to_git::save(
+            repo_dir,
+            &file_name,
+            &merged,
+            Some(&current),
+            author,
+            &message,
+        )?)
+    };
+    reference.delete()?;
+    Ok(id)
+}
+
+pub fn reject(repo_dir: &str, id: &str) -> Result<()> {
+    let (cn, uuid) = parse_id(id)?;
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let Ok(mut reference) = repo.find_reference(&ref_name(cn, uuid)) else {
+        return err!(LinksError::ProposalNotFound(String::from(id)));
+    };
+    reference.delete()?;
+    Ok(())
+}
+
+fn error_response(
+    what: &str,
+    e: lib_hyper_organizator::typedef::GenericError,
+) -> Result<Response<Body>> {
+    match e.downcast_ref() {
+        Some(LinksError::ProposalNotFound(_))
+        | Some(LinksError::DocumentNotFound(_))
+        | Some(LinksError::BadUuid(_))
+        | Some(LinksError::BadUserName(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Some(LinksError::NotOwner(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        Some(LinksError::MergeConflict(conflicts)) => Ok(Response::builder()
+            .status(StatusCode::CONFLICT)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(conflicts)?))?),
+        _ => {
+            error!("Failed to {}: {}", what, e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Owners see all the proposals, everybody else only their own
+pub async fn get_proposals(req: Request<Body>) -> Result<Response<Body>> {
+    let cn = String::from(get_user_name(&req)?);
+    let result = git_worker::run(move || {
+        let mut proposals = proposals(&CONFIG.storage_dir)?;
+        if !CONFIG.is_owner(&cn) {
+            proposals.retain(|p| p.author == cn);
+        }
+        Ok(proposals)
+    })
+    .await;
+    match result {
+        Ok(proposals) => serde_json::to_string(&proposals)?.to_json_response(),
+        Err(e) => error_response("list the proposals", e),
+    }
+}
+
+pub async fn accept_proposal(mut request: Request<Body>) -> Result<Response<Body>> {
+    let proposal: ProposalId = match parse_body(&mut request).await {
+        Ok(proposal) => proposal,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let owner = String::from(get_user_name(&request)?);
+    if !CONFIG.is_owner(&owner) {
+        return error_response("accept the proposal", Box::new(LinksError::NotOwner(owner)));
+    }
+    let Ok((cn, uuid)) = parse_id(&proposal.id) else {
+        return "bad proposal id".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let cn = String::from(cn);
+    let _guard = locks::lock_document(uuid).await;
+    info!("{} accepts proposal {}", owner, proposal.id);
+
+    let id = proposal.id.clone();
+    let result =
+        git_worker::run(move || accept(&CONFIG.storage_dir, &id, &CONFIG.signature(&cn)?, &owner))
+            .await;
+    match result {
+        Ok(Some(revision)) => format!(r#"{{"revision":"{}"}}"#, revision).to_json_response(),
+        Ok(None) => "Nothing to merge, the proposal was dropped"
+            .to_text_response_with_status(StatusCode::from_u16(254).unwrap()),
+        Err(e) => error_response("accept the proposal", e),
+    }
+}
+
+/// Owners reject proposals, the author can withdraw their own
+pub async fn reject_proposal(mut request: Request<Body>) -> Result<Response<Body>> {
+    let proposal: ProposalId = match parse_body(&mut request).await {
+        Ok(proposal) => proposal,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let cn = String::from(get_user_name(&request)?);
+    let is_author = proposal.id.split_once('/').map(|(author, _)| author) == Some(cn.as_str());
+    if !CONFIG.is_owner(&cn) && !is_author {
+        return error_response("reject the proposal", Box::new(LinksError::NotOwner(cn)));
+    }
+    info!("{} rejects proposal {}", cn, proposal.id);
+    match git_worker::run(move || reject(&CONFIG.storage_dir, &proposal.id)).await {
+        Ok(()) => "Proposal rejected".to_text_response(),
+        Err(e) => error_response("reject the proposal", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::test_repo;
+
+    #[test]
+    fn test_propose_and_accept() {
+        let uuid = "5b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let file_name = format!("{}.md", uuid);
+        let dir = test_repo("proposals", &file_name, "# Team\n- [a](http://a)\n");
+        let bob = Signature::now("bob", "_").unwrap();
+
+        propose(
+            &dir,
+            "bob",
+            uuid,
+            None,
+            "# Team\n- [a](http://a)\n- [b](http://b)\n",
+            &bob,
+            None,
+        )
+        .unwrap();
+        let (tip, _) = propose(
+            &dir,
+            "bob",
+            uuid,
+            None,
+            "# Team\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n",
+            &bob,
+            None,
+        )
+        .unwrap();
+        // nothing changes for everybody else until it is accepted
+        assert_eq!(
+            fs::read_to_string(Path::new(&dir).join(&file_name)).unwrap(),
+            "# Team\n- [a](http://a)\n"
+        );
+
+        let open = proposals(&dir).unwrap();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].id, format!("bob/{}", uuid));
+        assert_eq!(open[0].revision, tip.to_string());
+        assert_eq!(open[0].links.added.len(), 2);
+        assert!(open[0].diff.contains("+- [c](http://c)\n"));
+
+        // the owner edits the title in the meantime, the proposal merges on top of it
+        fs::write(
+            Path::new(&dir).join(&file_name),
+            "# Team links\n- [a](http://a)\n",
+        )
+        .unwrap();
+        save_to_git::commit(
+            &dir,
+            &[&file_name],
+            &Signature::now("alice", "_").unwrap(),
+            "title",
+        )
+        .unwrap();
+
+        let id = format!("bob/{}", uuid);
+        assert!(accept(&dir, &id, &bob, "alice").unwrap().is_some());
+        assert_eq!(
+            fs::read_to_string(Path::new(&dir).join(&file_name)).unwrap(),
+            "# Team links\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n"
+        );
+        assert!(proposals(&dir).unwrap().is_empty());
+        assert!(reject(&dir, &id).is_err());
+    }
+
+    #[test]
+    fn test_reject() {
+        let uuid = "6b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let file_name = format!("{}.md", uuid);
+        let dir = test_repo("reject", &file_name, "# Doc\n");
+        let bob = Signature::now("bob", "_").unwrap();
+
+        assert!(propose(&dir, "bob", uuid, None, "# Doc\n", &bob, None).is_err());
+        propose(&dir, "bob", uuid, None, "# Doc\nmore\n", &bob, Some("more")).unwrap();
+        assert_eq!(proposals(&dir).unwrap()[0].message, "more");
+        reject(&dir, &format!("bob/{}", uuid)).unwrap();
+        assert!(proposals(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_proposal_from_an_older_revision() {
+        let uuid = "7b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let file_name = format!("{}.md", uuid);
+        let loaded = "# Doc\n\n- [a](http://a)\n- [b](http://b)\n";
+        let dir = test_repo("propose-older", &file_name, loaded);
+        let revision = save_to_git::head_revision(&dir).unwrap();
+        let bob = Signature::now("bob", "_").unwrap();
+
+        // the owner changes the title after bob loaded the document
+        let retitled = "# Links\n\n- [a](http://a)\n- [b](http://b)\n";
+        fs::write(Path::new(&dir).join(&file_name), retitled).unwrap();
+        let alice = Signature::now("alice", "_").unwrap();
+        save_to_git::commit(&dir, &[&file_name], &alice, "title").unwrap();
+
+        let stale = propose(&dir, "bob", uuid, Some("not-a-revision"), "x", &bob, None);
+        assert!(matches!(
+            stale.unwrap_err().downcast_ref(),
+            Some(LinksError::RevisionNotFound(_))
+        ));
+        let added = "# Doc\n\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n";
+        let (_, merged) = propose(&dir, "bob", uuid, Some(&revision), added, &bob, None).unwrap();
+        assert_eq!(merged, None);
+        // another save from the same load keeps the first one
+        let changed = "# Doc\n\n- [a](https://a)\n- [b](http://b)\n";
+        let (_, merged) = propose(&dir, "bob", uuid, Some(&revision), changed, &bob, None).unwrap();
+        let both = "# Doc\n\n- [a](https://a)\n- [b](http://b)\n- [c](http://c)\n";
+        assert_eq!(merged.as_deref(), Some(both));
+
+        // accepting keeps the title
+        accept(&dir, &format!("bob/{}", uuid), &bob, "alice").unwrap();
+        assert_eq!(
+            fs::read_to_string(Path::new(&dir).join(&file_name)).unwrap(),
+            "# Links\n\n- [a](https://a)\n- [b](http://b)\n- [c](http://c)\n"
+        );
+    }
+}
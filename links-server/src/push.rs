@@ -0,0 +1,236 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use git2::{PushOptions, RemoteCallbacks, Repository};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::{info, warn};
+
+use crate::{
+    git_worker, locks,
+    router::{LinksError, CONFIG},
+    save_to_git,
+    utils::{get_epoch_ms, get_user_name, Result},
+};
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// One queue per remote, so a slow or broken remote does not hold back the others
+static QUEUES: OnceLock<Vec<Sender<()>>> = OnceLock::new();
+
+lazy_static! {
+    static ref STATUS: Mutex<HashMap<String, RemoteStatus>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct RemoteStatus {
+    pub url:                  String,
+    /// epoch ms
+    pub last_attempt:         Option<u128>,
+    /// epoch ms
+    pub last_success:         Option<u128>,
+    pub pushed_revision:      Option<String>,
+    pub last_error:           Option<String>,
+    pub consecutive_failures: u32,
+}
+
+/// Starts one background worker per configured remote
+pub fn start() {
+    let queues = CONFIG
+        .remotes
+        .iter()
+        .map(|url| {
+            // a single slot is enough, pending pushes are coalesced into one
+            let (tx, rx) = mpsc::channel(1);
+            STATUS.lock().unwrap().insert(
+                url.clone(),
+                RemoteStatus {
+                    url: url.clone(),
+                    ..Default::default()
+                },
+            );
+            tokio::spawn(worker(url.clone(), rx));
+            tx
+        })
+        .collect();
+    let _ = QUEUES.set(queues);
+    info!("push workers started for {} remotes", CONFIG.remotes.len());
+}
+
+/// Asks for a push to all the remotes, never blocks
+pub fn schedule() {
+    if let Some(queues) = QUEUES.get() {
+        for queue in queues {
+            // a full queue means a push is already pending and will pick up this commit too
+            let _ = queue.try_send(());
+        }
+    }
+}
+
+async fn worker(url: String, mut rx: Receiver<()>) {
+    while rx.recv().await.is_some() {
+        // the push seals HEAD, later saves go into commits of their own
+        push_with_retries(&url).await;
+    }
+}
+
+/// Pushes whatever is committed to every remote once, used on shutdown
+pub async fn flush() {
+    // saves in progress finish first and no new ones start
+    let _guard = locks::lock_working_tree().await;
+    for url in &CONFIG.remotes {
+        let remote = url.clone();
+        let result = git_worker::run(move || Ok(push_to(&CONFIG.storage_dir, &remote)?)).await;
+        record_attempt(url, 1, result.map_err(|e| e.to_string()));
+    }
+}
+
+async fn push_with_retries(url: &str) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let remote = url.to_string();
+        let result = git_worker::run(move || Ok(push_to(&CONFIG.storage_dir, &remote)?))
+            .await
+            .map_err(|e| e.to_string());
+
+        if record_attempt(url, attempt, result) {
+            return;
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Updates the status of the remote, returns true when the push succeeded
+fn record_attempt(url: &str, attempt: u32, result: std::result::Result<String, String>) -> bool {
+    let mut status = STATUS.lock().unwrap();
+    let status = status.entry(url.to_string()).or_default();
+    status.last_attempt = Some(get_epoch_ms());
+    match result {
+        Ok(revision) => {
+            info!("pushed {} to {}", revision, url);
+            status.last_success = status.last_attempt;
+            status.pushed_revision = Some(revision);
+            status.last_error = None;
+            status.consecutive_failures = 0;
+            true
+        }
+        Err(e) => {
+            warn!("push to {} failed, attempt {}: {}", url, attempt, e);
+            status.last_error = Some(e);
+            status.consecutive_failures += 1;
+            false
+        }
+    }
+}
+
+/// Pushes the current branch to the remote, returns the revision pushed.
+/// The commit is picked and sealed under the repository lock and pushed by its id, so a save
+/// landing during the push is neither sent along nor folded into what was sent.
+pub fn push_to(repo_dir: &str, url: &str) -> std::result::Result<String, git2::Error> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let (branch, revision) = {
+        let _guard = locks::lock_repository();
+        let head = repo.head()?;
+        let Some(branch) = head.name() else {
+            return Err(git2::Error::from_str("HEAD is not a branch"));
+        };
+        // an amended commit must never replace one that was already pushed
+        save_to_git::seal();
+        (branch.to_string(), head.peel_to_commit()?.id().to_string())
+    };
+
+    // the remote reports rejected references through the callback, not as an error
+    let rejected = RefCell::new(None);
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.push_update_reference(|reference, status| {
+        if let Some(status) = status {
+            *rejected.borrow_mut() = Some(format!("{} rejected: {}", reference, status));
+        }
+        Ok(())
+    });
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+
+    let mut remote = repo.remote_anonymous(url)?;
+    remote.push(&[format!("{}:{}", revision, branch)], Some(&mut options))?;
+    drop(options);
+
+    match rejected.into_inner() {
+        Some(message) => Err(git2::Error::from_str(&message)),
+        None => Ok(revision),
+    }
+}
+
+/// Only for the owners
+pub async fn get_push_status(req: Request<Body>) -> Result<Response<Body>> {
+    let user = get_user_name(&req)?;
+    if !CONFIG.is_owner(user) {
+        return LinksError::NotOwner(String::from(user))
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let status: Vec<RemoteStatus> = {
+        let status = STATUS.lock().unwrap();
+        CONFIG
+            .remotes
+            .iter()
+            .filter_map(|url| status.get(url).cloned())
+            .collect()
+    };
+    serde_json::to_string(&status)?.to_json_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::{test_repo, COALESCING};
+
+    #[test]
+    fn test_push_to_bare_repository() {
+        let dir = test_repo("push", "doc.md", "# Doc\n");
+        let bare = std::env::temp_dir().join(format!("links-push-bare-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&bare);
+        Repository::init_bare(&bare).unwrap();
+
+        let url = format!("file://{}", bare.display());
+        let revision = push_to(&dir, &url).unwrap();
+        assert_eq!(revision, save_to_git::head_revision(&dir).unwrap());
+        let pushed = Repository::open_bare(&bare).unwrap();
+        let head = pushed.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.id().to_string(), revision);
+
+        // what was pushed is never amended, so the next push is a fast forward
+        let _turn = COALESCING.lock().unwrap_or_else(|e| e.into_inner());
+        let author = git2::Signature::now("test", "_").unwrap();
+        let window = Duration::from_secs(60);
+        let save = |content: &str, previous: &str| {
+            save_to_git::save_coalesced(&dir, "doc.md", content, previous, &author, window, |_| {
+                String::from("saved via gui")
+            })
+            .unwrap()
+        };
+        let first = save("# Doc\na\n", "# Doc\n");
+        assert_eq!(push_to(&dir, &url).unwrap(), first.to_string());
+        let second = save("# Doc\nab\n", "# Doc\na\n");
+        let repo = Repository::open(&dir).unwrap();
+        assert_eq!(
+            repo.find_commit(second).unwrap().parent_id(0).unwrap(),
+            first
+        );
+        assert_eq!(push_to(&dir, &url).unwrap(), second.to_string());
+    }
+}
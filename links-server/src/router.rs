@@ -1,11 +1,6 @@
 use bytes::Buf;
 use lib_hyper_organizator::response_utils::{read_full_body, IntoResultHyperResponse};
-use std::{
-    collections::HashMap,
-    fs,
-    fs::File,
-    io::{BufWriter, Write},
-};
+use std::{collections::HashMap, fs, path::Path, time::Duration};
 use tracing::log::{error, warn};
 
 use hyper::{Body, Method, Request, Response, StatusCode};
@@ -17,31 +12,99 @@ use serde::{Deserialize, Serialize};
 use regex::Regex;
 use thiserror::Error as ThisError;
 
-use async_lock::Mutex;
-
 use crate::{static_files::serve_file, utils::Result};
 use lazy_static::lazy_static;
 
-use crate::save_to_git;
+use crate::diff::commit_summary;
+use crate::save_to_git::{self, ConflictHunk, MergeResult};
 use crate::utils::get_user_name;
+use crate::{folders, git_worker, locks};
+use git2::Signature;
 
 lazy_static! {
     pub static ref CONFIG: ApConfig = ApConfig::read_config();
-    static ref GLOBAL_LOCK: Mutex<usize> = Mutex::new(1);
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct Payload {
-    uuid:    String,
-    content: String,
+pub(crate) struct Payload {
+    pub(crate) uuid:     String,
+    pub(crate) content:  String,
+    /// the git revision the editor loaded the content from
+    #[serde(default)]
+    pub(crate) revision: Option<String>,
+    /// commit message, generated from the changes when missing
+    #[serde(default)]
+    pub(crate) message:  Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct SaveResponse {
+    pub(crate) revision: String,
+    /// present only when the content was merged with changes made by somebody else
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) merged:   Option<String>,
+    /// the proposal the save went to, for users who cannot write the document directly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) proposal: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ConflictResponse<'a> {
+    revision:  String,
+    conflicts: &'a [ConflictHunk],
 }
 
 #[derive(Deserialize, Debug)]
 pub struct ApConfig {
-    pub storage_dir:       String,
-    pub static_files_dir:  String,
-    pub click_buffer_size: usize,
-    pub static_files:      HashMap<String, FileDescriptor>,
+    pub storage_dir:         String,
+    pub static_files_dir:    String,
+    pub click_buffer_size:   usize,
+    pub static_files:        HashMap<String, FileDescriptor>,
+    /// maps certificate CNs to the identity recorded in git
+    #[serde(default)]
+    pub identities:          HashMap<String, Identity>,
+    /// git remotes the storage repository is pushed to after each commit
+    #[serde(default)]
+    pub remotes:             Vec<String>,
+    /// another links-server repository to fetch from, merge and push back to
+    #[serde(default)]
+    pub sync_remote:         Option<String>,
+    #[serde(default = "default_sync_interval")]
+    pub sync_interval_secs:  u64,
+    /// CNs allowed to write directly and to review proposals, everybody when empty
+    #[serde(default)]
+    pub owners:              Vec<String>,
+    /// autosaved drafts, one directory per user, kept out of the git repository
+    #[serde(default)]
+    pub drafts_dir:          Option<String>,
+    /// saves of the same document by the same user within this many seconds share one commit
+    #[serde(default)]
+    pub commit_window_secs:  u64,
+    /// threads running the git and file system work of the requests
+    #[serde(default = "default_git_threads")]
+    pub git_threads:         usize,
+    /// requests waiting for a git thread, further requests wait for a free slot
+    #[serde(default = "default_git_queue_size")]
+    pub git_queue_size:      usize,
+    /// bytes, larger uploads are refused
+    #[serde(default = "default_max_attachment_size")]
+    pub max_attachment_size: usize,
+}
+
+fn default_sync_interval() -> u64 {
+    300
+}
+
+fn default_git_threads() -> usize {
+    2
+}
+
+fn default_git_queue_size() -> usize {
+    64
+}
+
+fn default_max_attachment_size() -> usize {
+    10 * 1024 * 1024
 }
 
 #[derive(Deserialize, Debug)]
@@ -50,6 +113,12 @@ pub struct FileDescriptor {
     pub mime: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct Identity {
+    pub name:  String,
+    pub email: String,
+}
+
 impl ApConfig {
     fn read_config() -> ApConfig {
         let config = Config::builder()
@@ -59,6 +128,37 @@ impl ApConfig {
         let ap_config: ApConfig = config.get("application").unwrap();
         ap_config
     }
+
+    /// The git signature for a user, falls back to the CN when no identity is configured
+    pub fn signature(&self, cn: &str) -> std::result::Result<Signature<'static>, git2::Error> {
+        match self.identities.get(cn) {
+            Some(identity) => Signature::now(&identity.name, &identity.email),
+            None => Signature::now(cn, "_"),
+        }
+    }
+
+    /// Where the drafts go, on the storage volume unless configured otherwise.
+    /// The directory is hidden, so neither the catalog nor the folders list it.
+    pub fn drafts_dir(&self) -> String {
+        match &self.drafts_dir {
+            Some(dir) => dir.clone(),
+            None => format!("{}/.drafts", self.storage_dir),
+        }
+    }
+
+    /// Owners save straight to the history, everybody else saves proposals
+    pub fn is_owner(&self, cn: &str) -> bool {
+        self.owners.is_empty() || self.owners.iter().any(|owner| owner == cn)
+    }
+
+    /// The CN behind a commit author, the reverse of `signature`
+    pub fn cn_of<'a>(&'a self, name: &'a str, email: &str) -> &'a str {
+        self.identities
+            .iter()
+            .find(|(_, identity)| identity.name == name && identity.email == email)
+            .map(|(cn, _)| cn.as_str())
+            .unwrap_or(name)
+    }
 }
 
 #[derive(ThisError, Debug, PartialEq)]
@@ -69,6 +169,30 @@ pub enum LinksError {
     BadUserName(String),
     #[error("Content not changed")]
     ContentNotChanged,
+    #[error("Merge conflict")]
+    MergeConflict(Vec<ConflictHunk>),
+    #[error("Document not found {0}")]
+    DocumentNotFound(String),
+    #[error("Revision not found {0}")]
+    RevisionNotFound(String),
+    #[error("Proposal not found {0}")]
+    ProposalNotFound(String),
+    #[error("{0} is not an owner")]
+    NotOwner(String),
+    #[error("Bad snapshot name {0}")]
+    BadSnapshotName(String),
+    #[error("Snapshot already exists {0}")]
+    SnapshotExists(String),
+    #[error("Document is encrypted {0}")]
+    DocumentEncrypted(String),
+    #[error("Bad OPML {0}")]
+    BadOpml(String),
+    #[error("Bad folder name {0}")]
+    BadFolderName(String),
+    #[error("Folder not found {0}")]
+    FolderNotFound(String),
+    #[error("Folder already exists {0}")]
+    FolderExists(String),
 }
 
 macro_rules! err {
@@ -76,8 +200,9 @@ macro_rules! err {
         Err(Box::new($a))
     };
 }
+pub(crate) use err;
 
-fn verify_uuid(uuid: &str) -> Result<()> {
+pub(crate) fn verify_uuid(uuid: &str) -> Result<()> {
     lazy_static! {
         static ref UUID: Regex = Regex::new(r#"^[\da-f]{8}-([\da-f]{4}-){3}[\da-f]{12}$"#).unwrap();
     }
@@ -88,7 +213,7 @@ fn verify_uuid(uuid: &str) -> Result<()> {
     Ok(())
 }
 
-fn verify_user(user: &str) -> Result<&str> {
+pub(crate) fn verify_user(user: &str) -> Result<&str> {
     lazy_static! {
         static ref USER: Regex = Regex::new(r#"^[a-zA-Z0-9_-]+$"#).unwrap();
     }
@@ -122,29 +247,72 @@ mod test {
     }
 }
 
-async fn do_work(p: Payload, cn: &str) -> Result<String> {
+async fn do_work(p: Payload, cn: &str) -> Result<SaveResponse> {
     //println!("Json received: {:#?}", p);
 
-    let _guard = GLOBAL_LOCK.lock().await;
+    // the uuid names a lock, only well formed ones get one
+    verify_uuid(&p.uuid)?;
+    // writes to other documents go ahead, only the commit itself is serialised
+    let _guard = locks::lock_document(&p.uuid).await;
+
+    let cn = String::from(cn);
+    if !CONFIG.is_owner(&cn) {
+        return git_worker::run(move || crate::proposals::propose_document(p, &cn)).await;
+    }
+    git_worker::run(move || save_document(p, &cn)).await
+}
 
+/// The blocking part of a save, runs on a git worker
+fn save_document(p: Payload, cn: &str) -> Result<SaveResponse> {
     // validate the uuid is the right format
     verify_uuid(&p.uuid)?;
     let user = verify_user(cn)?;
-    let file_name = format!("{}/{}.md", CONFIG.storage_dir, p.uuid);
+    let file_name = folders::document_path(&CONFIG.storage_dir, &p.uuid)?;
+    let current_content = fs::read_to_string(Path::new(&CONFIG.storage_dir).join(&file_name))?;
+
+    // if the editor tells us what it started from, merge with whatever was saved since
+    let (content, merged) = match &p.revision {
+        Some(revision) => match save_to_git::merge(
+            &CONFIG.storage_dir,
+            &file_name,
+            revision,
+            &current_content,
+            &p.content,
+        )? {
+            MergeResult::Clean(content) => {
+                let merged = content != p.content;
+                (content, merged)
+            }
+            MergeResult::Conflict(hunks) => return err!(LinksError::MergeConflict(hunks)),
+        },
+        None => (p.content, false),
+    };
+
     // check if the file content is changed
-    let current_content = fs::read_to_string(&file_name)?;
-    if current_content == p.content {
+    if current_content == content {
         return err!(LinksError::ContentNotChanged);
     }
 
-    let file = File::create(file_name)?;
-    let mut out = BufWriter::new(&file);
-    write!(out, "{}", p.content)?;
-    drop(out);
-
-    save_to_git::commit(&CONFIG.storage_dir, user)?;
+    // quick successive saves are folded into one commit, the summary then covers all of them
+    let message = |base: &str| match p.message.as_deref().map(str::trim) {
+        Some(message) if !message.is_empty() => String::from(message),
+        _ => commit_summary(&p.uuid, base, &content),
+    };
+    let revision = save_to_git::save_coalesced(
+        &CONFIG.storage_dir,
+        &file_name,
+        &content,
+        &current_content,
+        &CONFIG.signature(user)?,
+        Duration::from_secs(CONFIG.commit_window_secs),
+        message,
+    )?;
 
-    Ok(String::from("Standard response"))
+    Ok(SaveResponse {
+        revision: revision.to_string(),
+        merged:   merged.then_some(content),
+        proposal: None,
+    })
 }
 
 async fn save_links(mut request: Request<Body>) -> Result<Response<Body>> {
@@ -159,13 +327,35 @@ async fn save_links(mut request: Request<Body>) -> Result<Response<Body>> {
     };
 
     let user = get_user_name(&request)?;
-    match do_work(p, user).await {
-        Ok(content) => content.to_text_response(),
-        Err(e) if e.downcast_ref() == Some(&LinksError::ContentNotChanged) => {
-            "Content has not changed since last save"
-                .to_text_response_with_status(StatusCode::from_u16(254).unwrap())
+    save(p, user).await
+}
+
+/// Saves the content and maps the outcome to a response, shared with publishing a draft
+pub(crate) async fn save(p: Payload, user: &str) -> Result<Response<Body>> {
+    let e = match do_work(p, user).await {
+        Ok(response) => return serde_json::to_string(&response)?.to_json_response(),
+        Err(e) => e,
+    };
+    match e.downcast_ref() {
+        Some(LinksError::ContentNotChanged) => "Content has not changed since last save"
+            .to_text_response_with_status(StatusCode::from_u16(254).unwrap()),
+        Some(LinksError::RevisionNotFound(_)) | Some(LinksError::BadUuid(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::BAD_REQUEST),
+        Some(LinksError::MergeConflict(conflicts)) => {
+            warn!("Save rejected, merge conflict");
+            let revision =
+                git_worker::run(|| Ok(save_to_git::head_revision(&CONFIG.storage_dir)?)).await?;
+            let body = serde_json::to_string(&ConflictResponse {
+                revision,
+                conflicts,
+            })?;
+            Ok(Response::builder()
+                .status(StatusCode::CONFLICT)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))?)
         }
-        Err(e) => {
+        _ => {
             error!("Failed to save the links: {}", e);
             e.to_string()
                 .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -176,6 +366,44 @@ async fn save_links(mut request: Request<Body>) -> Result<Response<Body>> {
 pub async fn request_handler(req: Request<Body>) -> Result<Response<Body>> {
     match (req.method(), req.uri().path()) {
         (&Method::POST, "/save_links") => save_links(req).await,
+        (&Method::POST, "/create_links") => crate::documents::create_links(req).await,
+        (&Method::POST, "/delete_links") => crate::documents::delete_links(req).await,
+        (&Method::POST, "/restore_links") => crate::documents::restore_links(req).await,
+        (&Method::POST, "/revert_links") => crate::documents::revert_links(req).await,
+        (&Method::POST, "/move_links") => crate::folders::move_links(req).await,
+        (&Method::GET, "/folders") => crate::folders::get_folders(req).await,
+        (&Method::POST, "/folders") => crate::folders::create_folder(req).await,
+        (&Method::POST, "/rename_folder") => crate::folders::rename_folder(req).await,
+        (&Method::POST, "/move_folder") => crate::folders::move_folder(req).await,
+        (&Method::POST, "/import_bookmarks") => crate::bookmarks::import_bookmarks(req).await,
+        (&Method::GET, "/export_bookmarks") => crate::bookmarks::export_bookmarks(req).await,
+        (&Method::GET, "/export_opml") => crate::opml::export_opml(req).await,
+        (&Method::POST, "/import_opml") => crate::opml::import_opml(req).await,
+        (&Method::POST, "/attachments") => crate::attachments::upload_attachment(req).await,
+        (&Method::POST, "/gc_attachments") => crate::attachments::gc_attachments(req).await,
+        (&Method::GET, path) if path.starts_with("/attachments/") => {
+            crate::attachments::get_attachment(req).await
+        }
+        (&Method::GET, "/backup") => crate::backup::get_backup(req).await,
+        (&Method::GET, "/trash") => crate::documents::get_trash(req).await,
+        (&Method::POST, "/save_draft") => crate::drafts::save_draft(req).await,
+        (&Method::POST, "/publish_draft") => crate::drafts::publish_draft(req).await,
+        (&Method::POST, "/discard_draft") => crate::drafts::discard_draft(req).await,
+        (&Method::GET, "/snapshots") => crate::snapshots::get_snapshots(req).await,
+        (&Method::POST, "/snapshots") => crate::snapshots::create_snapshot(req).await,
+        (&Method::GET, "/snapshot_archive") => crate::snapshots::get_snapshot_archive(req).await,
+        (&Method::GET, "/proposals") => crate::proposals::get_proposals(req).await,
+        (&Method::POST, "/accept_proposal") => crate::proposals::accept_proposal(req).await,
+        (&Method::POST, "/reject_proposal") => crate::proposals::reject_proposal(req).await,
+        (&Method::GET, "/drafts") => crate::drafts::get_drafts(req).await,
+        (&Method::GET, "/draft") => crate::drafts::get_draft(req).await,
+        (&Method::GET, "/history") => crate::history::get_history(req).await,
+        (&Method::GET, "/blame") => crate::history::get_blame(req).await,
+        (&Method::GET, "/diff") => crate::diff::get_diff(req, false).await,
+        (&Method::GET, "/link_diff") => crate::diff::get_diff(req, true).await,
+        (&Method::GET, "/admin/push_status") => crate::push::get_push_status(req).await,
+        (&Method::GET, "/admin/sync_status") => crate::sync::get_sync_status(req).await,
+        (&Method::POST, "/admin/sync_dismiss") => crate::sync::dismiss_conflict(req).await,
         (&Method::POST, "/register_click") => crate::links::register_click(req).await,
         (&Method::GET, "/link_stats") => crate::links::get_link_stats(req).await,
         (&Method::GET, "/catalog") => crate::catalog::get_catalog(req).await,
@@ -1,38 +1,721 @@
-use git2::Repository;
 use git2::Signature;
+use git2::{DiffOptions, ErrorCode, Oid, Patch, Repository};
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
 use std::path::Path;
-use tracing::trace;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{error, info, trace};
 
-pub fn commit(repo_dir: &str, author: &str) -> Result<(), git2::Error> {
+use crate::router::{err, LinksError};
+
+/// The last commit made through [`commit_or_amend`], later saves inside the window are folded into it
+struct OpenCommit {
+    id:       Oid,
+    repo_dir: String,
+    path:     String,
+    name:     String,
+    email:    String,
+    started:  Instant,
+}
+
+static OPEN: Mutex<Option<OpenCommit>> = Mutex::new(None);
+
+/// Commits the given paths, relative to the repository root.
+/// Paths missing from the working tree are recorded as deleted, nothing else is staged.
+pub fn commit(
+    repo_dir: &str,
+    paths: &[&str],
+    author: &Signature,
+    message: &str,
+) -> Result<Oid, git2::Error> {
     trace!("committing to git repo: {}", repo_dir);
+    // the working tree files are already written, only the index and HEAD are shared
+    let _guard = crate::locks::lock_repository();
     let repo = Repository::open(Path::new(repo_dir))?;
-
     trace!("repo opened");
+    commit_locked(&repo, repo_dir, paths, author, message)
+}
+
+/// Writes the paths to the index and returns the resulting tree
+fn stage<'r>(
+    repo: &'r Repository,
+    repo_dir: &str,
+    paths: &[&str],
+) -> Result<git2::Tree<'r>, git2::Error> {
     let mut index = repo.index()?;
     trace!("index opened");
 
-    index.add_all(["*.md"].iter(), git2::IndexAddOption::DEFAULT, None)?;
-    trace!("added all files to index");
+    for path in paths {
+        if Path::new(repo_dir).join(path).is_file() {
+            index.add_path(Path::new(path))?;
+            trace!("added {} to index", path);
+        } else {
+            index.remove_path(Path::new(path))?;
+            trace!("removed {} from index", path);
+        }
+    }
 
     index.write()?;
     trace!("index written");
 
     let tree_id = index.write_tree()?;
     trace!("tree written");
-    let tree = repo.find_tree(tree_id)?;
+    repo.find_tree(tree_id)
+}
+
+fn commit_locked(
+    repo: &Repository,
+    repo_dir: &str,
+    paths: &[&str],
+    author: &Signature,
+    message: &str,
+) -> Result<Oid, git2::Error> {
+    let tree = stage(repo, repo_dir, paths)?;
     trace!("tree found");
-    let parent_commit = repo.head()?.peel_to_commit()?;
-    trace!("parent commit found");
-
-    let author = Signature::now(author, "_")?;
-    trace!("author found");
-    repo.commit(
-        Some("HEAD"),
-        &author,
-        &author,
-        "saved via gui",
-        &tree,
-        &[&parent_commit],
+    // a repository without commits has no parent to build on
+    let parent_commit = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    trace!("parent commit found: {}", parent_commit.is_some());
+    let parents: Vec<_> = parent_commit.iter().collect();
+
+    let id = repo.commit(Some("HEAD"), author, author, message, &tree, &parents)?;
+    // replication runs in the background, a failing remote does not fail the commit
+    crate::push::schedule();
+    Ok(id)
+}
+
+/// Commits a single file, or amends HEAD when it is the open commit for the same file and author
+/// and the window has not passed yet. `message` gets the content the file had before the commit,
+/// for an amended commit that is the content before the first of the folded saves.
+pub fn commit_or_amend(
+    repo_dir: &str,
+    file_name: &str,
+    author: &Signature,
+    window: Duration,
+    message: impl FnOnce(&str) -> String,
+) -> Result<Oid, git2::Error> {
+    let _guard = crate::locks::lock_repository();
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head().and_then(|head| head.peel_to_commit()).ok();
+    let name = author.name().unwrap_or_default();
+    let email = author.email().unwrap_or_default();
+
+    let mut open = OPEN.lock().unwrap();
+    let foldable = match (open.as_ref(), head.as_ref()) {
+        (Some(open), Some(head)) => {
+            open.id == head.id()
+                && open.repo_dir == repo_dir
+                && open.path == file_name
+                && open.name == name
+                && open.email == email
+                && open.started.elapsed() < window
+        }
+        _ => false,
+    };
+
+    if let (true, Some(head), Some(open)) = (foldable, head.as_ref(), open.as_mut()) {
+        let base = match head.parent(0) {
+            Ok(parent) => content_at(&repo, &parent.id().to_string(), file_name)?,
+            Err(_) => String::new(),
+        };
+        let tree = stage(&repo, repo_dir, &[file_name])?;
+        let id = head.amend(
+            Some("HEAD"),
+            None,
+            Some(author),
+            None,
+            Some(&message(&base)),
+            Some(&tree),
+        )?;
+        trace!("amended {} into {}", head.id(), id);
+        open.id = id;
+        crate::push::schedule();
+        return Ok(id);
+    }
+
+    let base = match head.as_ref() {
+        Some(head) => content_at(&repo, &head.id().to_string(), file_name)?,
+        None => String::new(),
+    };
+    let id = commit_locked(&repo, repo_dir, &[file_name], author, &message(&base))?;
+    *open = Some(OpenCommit {
+        id,
+        repo_dir: String::from(repo_dir),
+        path: String::from(file_name),
+        name: String::from(name),
+        email: String::from(email),
+        started: Instant::now(),
+    });
+    Ok(id)
+}
+
+/// Nothing is folded into the current HEAD any more, called before HEAD leaves the machine
+pub fn seal() {
+    *OPEN.lock().unwrap() = None;
+}
+
+/// Replaces the file in one step. The content goes to a temporary file next to it,
+/// is flushed to disk and renamed over the original, so a crash leaves either version but never half of one.
+pub fn write_atomic(path: &Path, content: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let Some(file_name) = path.file_name() else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("not a file: {}", path.display()),
+        ));
+    };
+    // hidden and not ending in .md, so neither the catalog nor git pick it up
+    let temp = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    let mut file = fs::File::create(&temp)?;
+    if let Err(e) = file
+        .write_all(content.as_ref())
+        .and_then(|_| file.sync_all())
+    {
+        drop(file);
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    drop(file);
+    fs::rename(&temp, path)?;
+    // the rename itself is only durable once the directory is synced
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes a document and commits it. If the commit fails the previous content is put back,
+/// `None` meaning there was no file, so the working tree and HEAD never disagree.
+pub fn save(
+    repo_dir: &str,
+    file_name: &str,
+    content: &str,
+    previous: Option<&str>,
+    author: &Signature,
+    message: &str,
+) -> crate::utils::Result<Oid> {
+    let path = Path::new(repo_dir).join(file_name);
+    write_atomic(&path, content)?;
+    commit(repo_dir, &[file_name], author, message)
+        .or_else(|e| restore(repo_dir, file_name, previous, e))
+}
+
+/// Like [`save`], but successive saves of the same author inside the window end up in one commit
+pub fn save_coalesced(
+    repo_dir: &str,
+    file_name: &str,
+    content: &str,
+    previous: &str,
+    author: &Signature,
+    window: Duration,
+    message: impl FnOnce(&str) -> String,
+) -> crate::utils::Result<Oid> {
+    let path = Path::new(repo_dir).join(file_name);
+    write_atomic(&path, content)?;
+    commit_or_amend(repo_dir, file_name, author, window, message)
+        .or_else(|e| restore(repo_dir, file_name, Some(previous), e))
+}
+
+/// Puts the previous content back after a failed commit and hands the error on
+fn restore(
+    repo_dir: &str,
+    file_name: &str,
+    previous: Option<&str>,
+    e: git2::Error,
+) -> crate::utils::Result<Oid> {
+    error!(
+        "commit of {} failed, restoring the previous content: {}",
+        file_name, e
+    );
+    let path = Path::new(repo_dir).join(file_name);
+    let restored = match previous {
+        Some(previous) => write_atomic(&path, previous),
+        None => fs::remove_file(&path),
+    };
+    if let Err(restore_error) = restored {
+        error!("could not restore {}: {}", file_name, restore_error);
+    }
+    unstage(repo_dir, &[file_name]);
+    Err(e.into())
+}
+
+/// The commit may have failed after the index was written, put the entries back as in HEAD
+pub(crate) fn unstage(repo_dir: &str, paths: &[&str]) {
+    let _guard = crate::locks::lock_repository();
+    let Ok(repo) = Repository::open(Path::new(repo_dir)) else {
+        return;
+    };
+    let result = repo
+        .head()
+        .and_then(|head| head.peel(git2::ObjectType::Commit))
+        .and_then(|head| repo.reset_default(Some(&head), paths));
+    if let Err(e) = result {
+        error!("could not reset the index entries of {:?}: {}", paths, e);
+    }
+}
+
+/// What the first commit takes: at the top only the documents, the click log stays out, below it
+/// everything in the folders, the trash and the attachments. Hidden directories like `.git` and
+/// the drafts are left out, the `.folder` markers are not.
+fn initial_files(repo_dir: &Path, prefix: &str, files: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in fs::read_dir(repo_dir.join(prefix))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && !name.starts_with('.') {
+            initial_files(repo_dir, &path, files)?;
+        } else if file_type.is_file() && (!prefix.is_empty() || name.ends_with(".md")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Makes sure the storage directory is a git repository with at least one commit.
+/// A fresh repository gets an initial commit with the documents already there.
+pub fn init(repo_dir: &str) -> Result<(), git2::Error> {
+    let repo = match Repository::open(Path::new(repo_dir)) {
+        Ok(repo) => repo,
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            info!("initializing a git repository in {}", repo_dir);
+            fs::create_dir_all(repo_dir).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+            Repository::init(Path::new(repo_dir))?
+        }
+        Err(e) => return Err(e),
+    };
+    match repo.head() {
+        Ok(_) => return Ok(()),
+        Err(e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    let mut files = Vec::new();
+    initial_files(Path::new(repo_dir), "", &mut files)
+        .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    let paths: Vec<&str> = files.iter().map(String::as_str).collect();
+    info!("initial commit with {} files", paths.len());
+    commit(
+        repo_dir,
+        &paths,
+        &Signature::now("links-server", "_")?,
+        "initial commit",
     )?;
     Ok(())
 }
+
+/// The commit hash HEAD points to, this is the revision handed to the editor
+pub fn head_revision(repo_dir: &str) -> Result<String, git2::Error> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head()?.peel_to_commit()?;
+    Ok(head.id().to_string())
+}
+
+/// One side of a conflict, a change relative to the common base
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Hunk {
+    pub base_start: u32,
+    pub base_lines: u32,
+    pub removed:    String,
+    pub added:      String,
+}
+
+/// Our change and their change touch the same lines of the base
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ConflictHunk {
+    pub ours:   Hunk,
+    pub theirs: Hunk,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MergeResult {
+    Clean(String),
+    Conflict(Vec<ConflictHunk>),
+}
+
+/// Content of a file as it was in the given revision, empty if the file did not exist then
+fn content_at(repo: &Repository, revision: &str, file_name: &str) -> Result<String, git2::Error> {
+    let tree = repo.revparse_single(revision)?.peel_to_commit()?.tree()?;
+    let id = match tree.get_path(Path::new(file_name)) {
+        Ok(entry) => entry.id(),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            // the document may have been in another folder back then
+            let uuid = Path::new(file_name)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default();
+            match crate::folders::find_in_tree(&tree, uuid) {
+                Some((_, id)) => id,
+                None => return Ok(String::new()),
+            }
+        }
+        Err(e) => return Err(e),
+    };
+    let blob = repo.find_blob(id)?;
+    Ok(String::from_utf8_lossy(blob.content()).into_owned())
+}
+
+/// Tree holding a single file, used to feed the file level merge
+fn single_file_tree<'r>(
+    repo: &'r Repository,
+    name: &Path,
+    content: &str,
+) -> Result<git2::Tree<'r>, git2::Error> {
+    let blob = repo.blob(content.as_bytes())?;
+    let mut builder = repo.treebuilder(None)?;
+    builder.insert(name, blob, 0o100644)?;
+    repo.find_tree(builder.write()?)
+}
+
+/// Three way merge of a file in the repository.
+/// `base` is the revision the editor loaded, `ours` is what is currently on disk
+/// and `theirs` is what the editor is trying to save.
+pub fn merge(
+    repo_dir: &str,
+    file_name: &str,
+    base: &str,
+    ours: &str,
+    theirs: &str,
+) -> crate::utils::Result<MergeResult> {
+    trace!("merging {} against base {}", file_name, base);
+    let repo = Repository::open(Path::new(repo_dir))?;
+    // the base comes from the client, anything that is not a commit here is its mistake
+    let Ok(base) = repo
+        .revparse_single(base)
+        .and_then(|base| base.peel_to_commit())
+    else {
+        return err!(LinksError::RevisionNotFound(String::from(base)));
+    };
+    let base_content = content_at(&repo, &base.id().to_string(), file_name)?;
+    if base_content == ours {
+        // nobody else touched the file since it was loaded
+        return Ok(MergeResult::Clean(String::from(theirs)));
+    }
+
+    // only the name counts for the merge, tree entries can not hold a whole path
+    let name = Path::new(Path::new(file_name).file_name().unwrap_or_default());
+    let ancestor_tree = single_file_tree(&repo, name, &base_content)?;
+    let our_tree = single_file_tree(&repo, name, ours)?;
+    let their_tree = single_file_tree(&repo, name, theirs)?;
+    let index = repo.merge_trees(&ancestor_tree, &our_tree, &their_tree, None)?;
+
+    if index.has_conflicts() {
+        trace!("merge has conflicts");
+        return Ok(MergeResult::Conflict(conflict_hunks(
+            &base_content,
+            ours,
+            theirs,
+        )?));
+    }
+
+    let Some(entry) = index.get_path(name, 0) else {
+        // both sides deleted everything
+        return Ok(MergeResult::Clean(String::new()));
+    };
+    let blob = repo.find_blob(entry.id)?;
+    Ok(MergeResult::Clean(
+        String::from_utf8_lossy(blob.content()).into_owned(),
+    ))
+}
+
+/// Changes made to `base` to obtain `changed`, without context lines
+fn hunks(base: &str, changed: &str) -> Result<Vec<Hunk>, git2::Error> {
+    let mut options = DiffOptions::new();
+    options.context_lines(0);
+    let patch = Patch::from_buffers(
+        base.as_bytes(),
+        None,
+        changed.as_bytes(),
+        None,
+        Some(&mut options),
+    )?;
+    let mut hunks = Vec::with_capacity(patch.num_hunks());
+    for h in 0..patch.num_hunks() {
+        let (diff_hunk, line_count) = patch.hunk(h)?;
+        let mut hunk = Hunk {
+            base_start: diff_hunk.old_start(),
+            base_lines: diff_hunk.old_lines(),
+            removed:    String::new(),
+            added:      String::new(),
+        };
+        for l in 0..line_count {
+            let line = patch.line_in_hunk(h, l)?;
+            let content = String::from_utf8_lossy(line.content());
+            match line.origin() {
+                '-' => hunk.removed.push_str(&content),
+                '+' => hunk.added.push_str(&content),
+                _ => (),
+            }
+        }
+        hunks.push(hunk);
+    }
+    Ok(hunks)
+}
+
+/// Base line range touched by a hunk, insertions get an empty range after `base_start`
+fn base_range(hunk: &Hunk) -> (u32, u32) {
+    if hunk.base_lines == 0 {
+        (hunk.base_start + 1, hunk.base_start + 1)
+    } else {
+        (hunk.base_start, hunk.base_start + hunk.base_lines)
+    }
+}
+
+/// Like git, changes that touch or are adjacent to each other are considered conflicting
+fn overlaps(a: &Hunk, b: &Hunk) -> bool {
+    let (a_start, a_end) = base_range(a);
+    let (b_start, b_end) = base_range(b);
+    a_start <= b_end && b_start <= a_end
+}
+
+pub fn conflict_hunks(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+) -> Result<Vec<ConflictHunk>, git2::Error> {
+    let their_hunks = hunks(base, theirs)?;
+    let mut conflicts = Vec::new();
+    for our_hunk in hunks(base, ours)? {
+        for their_hunk in their_hunks.iter().filter(|h| overlaps(&our_hunk, h)) {
+            conflicts.push(ConflictHunk {
+                ours:   our_hunk.clone(),
+                theirs: their_hunk.clone(),
+            });
+        }
+    }
+    Ok(conflicts)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+
+    /// The open commit is shared by the whole process, tests that fold saves take turns
+    pub(crate) static COALESCING: Mutex<()> = Mutex::new(());
+
+    /// Fresh repository in the temp dir with one commit holding `file_name`
+    pub(crate) fn test_repo(name: &str, file_name: &str, content: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("links-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        if let Some(folder) = dir.join(file_name).parent() {
+            fs::create_dir_all(folder).unwrap();
+        }
+        fs::write(dir.join(file_name), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(file_name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let author = Signature::now("test", "_").unwrap();
+        repo.commit(Some("HEAD"), &author, &author, "initial", &tree, &[])
+            .unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_save_restores_on_failed_commit() {
+        let dir = std::env::temp_dir().join(format!("links-test-save-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("doc.md");
+        fs::write(&path, "old\n").unwrap();
+        let dir = dir.to_str().unwrap();
+        let author = Signature::now("test", "_").unwrap();
+
+        // not a repository, so the commit fails
+        assert!(save(dir, "doc.md", "new\n", Some("old\n"), &author, "edit").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old\n");
+        assert!(save(dir, "new.md", "new\n", None, &author, "create").is_err());
+        assert!(!Path::new(dir).join("new.md").exists());
+        assert!(!Path::new(dir).join(".doc.md.tmp").exists());
+
+        Repository::init(dir).unwrap();
+        save(dir, "doc.md", "new\n", Some("old\n"), &author, "edit").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(
+            fs::read_dir(dir).unwrap().count(),
+            2,
+            "only doc.md and .git"
+        );
+    }
+
+    #[test]
+    fn test_coalesced_saves() {
+        let _turn = COALESCING.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = test_repo("coalesce", "doc.md", "# Doc\n");
+        let alice = Signature::now("alice", "_").unwrap();
+        let bob = Signature::now("bob", "_").unwrap();
+        let window = Duration::from_secs(60);
+        let summary = |base: &str| format!("from {:?}", base);
+        let commits = |dir: &str| {
+            let repo = Repository::open(dir).unwrap();
+            let mut walk = repo.revwalk().unwrap();
+            walk.push_head().unwrap();
+            walk.count()
+        };
+
+        let first = save_coalesced(
+            &dir,
+            "doc.md",
+            "# Doc\na\n",
+            "# Doc\n",
+            &alice,
+            window,
+            summary,
+        )
+        .unwrap();
+        let second = save_coalesced(
+            &dir,
+            "doc.md",
+            "# Doc\nab\n",
+            "# Doc\na\n",
+            &alice,
+            window,
+            summary,
+        )
+        .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(commits(&dir), 2);
+        let repo = Repository::open(&dir).unwrap();
+        let head = repo.find_commit(second).unwrap();
+        assert_eq!(head.message(), Some("from \"# Doc\\n\""));
+        assert_eq!(content_at(&repo, "HEAD", "doc.md").unwrap(), "# Doc\nab\n");
+
+        // another author starts a new commit
+        save_coalesced(
+            &dir,
+            "doc.md",
+            "# Doc\nabc\n",
+            "# Doc\nab\n",
+            &bob,
+            window,
+            summary,
+        )
+        .unwrap();
+        assert_eq!(commits(&dir), 3);
+        // and so does anything after the commit was sealed for a push
+        seal();
+        save_coalesced(
+            &dir,
+            "doc.md",
+            "# Doc\nabcd\n",
+            "# Doc\nabc\n",
+            &bob,
+            window,
+            summary,
+        )
+        .unwrap();
+        assert_eq!(commits(&dir), 4);
+        // no window, no folding
+        save_coalesced(
+            &dir,
+            "doc.md",
+            "# Doc\n",
+            "# Doc\nabcd\n",
+            &bob,
+            Duration::ZERO,
+            summary,
+        )
+        .unwrap();
+        assert_eq!(commits(&dir), 5);
+    }
+
+    #[test]
+    fn test_init_and_scoped_commit() {
+        let dir = std::env::temp_dir().join(format!("links-init-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.md"), "# A\n").unwrap();
+        fs::write(dir.join("click.log"), "a\n").unwrap();
+        fs::create_dir_all(dir.join("work/reading")).unwrap();
+        fs::write(dir.join("work/.folder"), "").unwrap();
+        fs::write(dir.join("work/reading/c.md"), "# C\n").unwrap();
+        fs::create_dir_all(dir.join(".drafts/alice")).unwrap();
+        fs::write(dir.join(".drafts/alice/c.json"), "{}").unwrap();
+        let dir = dir.to_str().unwrap();
+
+        init(dir).unwrap();
+        let repo = Repository::open(dir).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_count(), 0);
+        let tree = head.tree().unwrap();
+        for path in ["a.md", "work/.folder", "work/reading/c.md"] {
+            assert!(tree.get_path(Path::new(path)).is_ok(), "{}", path);
+        }
+        assert!(tree.get_path(Path::new("click.log")).is_err());
+        assert!(tree.get_path(Path::new(".drafts")).is_err());
+        // a second call leaves the repository alone
+        init(dir).unwrap();
+        assert_eq!(head_revision(dir).unwrap(), head.id().to_string());
+
+        // only the named paths make it into the commit
+        fs::write(Path::new(dir).join("b.md"), "# B\n").unwrap();
+        fs::write(Path::new(dir).join("stray.md"), "# Stray\n").unwrap();
+        fs::remove_file(Path::new(dir).join("a.md")).unwrap();
+        let author = Signature::now("test", "_").unwrap();
+        let id = commit(dir, &["a.md", "b.md"], &author, "scoped").unwrap();
+        let tree = repo.find_commit(id).unwrap().tree().unwrap();
+        assert!(tree.get_path(Path::new("a.md")).is_err());
+        assert!(tree.get_path(Path::new("b.md")).is_ok());
+        assert!(tree.get_path(Path::new("stray.md")).is_err());
+    }
+
+    #[test]
+    fn test_merge_disjoint_changes() {
+        let base = "# Title\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n";
+        let dir = test_repo("merge-clean", "doc.md", base);
+        let revision = head_revision(&dir).unwrap();
+        let ours = "# Title\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n- [d](http://d)\n";
+        let theirs = "# New title\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n";
+        let merged = merge(&dir, "doc.md", &revision, ours, theirs).unwrap();
+        assert_eq!(
+            merged,
+            MergeResult::Clean(String::from(
+                "# New title\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n- [d](http://d)\n"
+            ))
+        );
+        // the same inside a folder
+        let dir = test_repo("merge-folder", "folder/doc.md", base);
+        let revision = head_revision(&dir).unwrap();
+        let merged = merge(&dir, "folder/doc.md", &revision, ours, theirs).unwrap();
+        assert_eq!(
+            merged,
+            MergeResult::Clean(String::from(
+                "# New title\n- [a](http://a)\n- [b](http://b)\n- [c](http://c)\n- [d](http://d)\n"
+            ))
+        );
+        let e = merge(&dir, "doc.md", "not-a-revision", ours, theirs).unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&LinksError::RevisionNotFound(String::from(
+                "not-a-revision"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let base = "# Title\n- [a](http://a)\n";
+        let dir = test_repo("merge-conflict", "doc.md", base);
+        let revision = head_revision(&dir).unwrap();
+        let ours = "# Title\n- [a](http://ours)\n";
+        let theirs = "# Title\n- [a](http://theirs)\n";
+        let MergeResult::Conflict(conflicts) = merge(&dir, "doc.md", &revision, ours, theirs).unwrap()
+        else {
+            panic!("expected a conflict");
+        };
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].ours.added, "- [a](http://ours)\n");
+        assert_eq!(conflicts[0].theirs.added, "- [a](http://theirs)\n");
+        assert_eq!(conflicts[0].theirs.removed, "- [a](http://a)\n");
+    }
+}
@@ -0,0 +1,255 @@
+use std::{io::Write, path::Path};
+
+use git2::{Commit, ErrorCode, ObjectType, Oid, Repository, Signature};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use lib_hyper_organizator::response_utils::{parse_body, IntoResultHyperResponse};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    archive::{self, Archive, Format},
+    backup::streamed_body,
+    git_worker,
+    router::{err, LinksError, CONFIG},
+    utils::{get_user_name, parse_query, Result},
+};
+
+/// A named state of the whole collection, kept as an annotated tag
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Snapshot {
+    pub name:      String,
+    /// the commit tagged
+    pub revision:  String,
+    pub author:    String,
+    pub email:     String,
+    /// seconds since the epoch
+    pub timestamp: i64,
+    pub message:   String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewSnapshot {
+    name:    String,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Names end up in urls and in `refs/tags/`, so they are kept simple
+fn verify_name(name: &str) -> Result<()> {
+    lazy_static! {
+        static ref NAME: Regex = Regex::new(r#"^[A-Za-z0-9][A-Za-z0-9._-]{0,99}$"#).unwrap();
+    }
+    if !NAME.is_match(name) || name.ends_with(".lock") || name.contains("..") {
+        return err!(LinksError::BadSnapshotName(String::from(name)));
+    }
+    Ok(())
+}
+
+/// Tags HEAD with the name, an existing snapshot is never moved
+pub fn create(repo_dir: &str, name: &str, tagger: &Signature, message: &str) -> Result<Oid> {
+    verify_name(name)?;
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head()?.peel(ObjectType::Commit)?;
+    match repo.tag(name, &head, tagger, message, false) {
+        Ok(id) => {
+            info!("snapshot {} of {} in tag {}", name, head.id(), id);
+            Ok(head.id())
+        }
+        Err(e) if e.code() == ErrorCode::Exists => {
+            err!(LinksError::SnapshotExists(String::from(name)))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The annotated tags, newest first. Lightweight tags were not made here and are left out.
+pub fn list(repo_dir: &str) -> Result<Vec<Snapshot>> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let mut snapshots = Vec::new();
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let reference = repo.find_reference(&format!("refs/tags/{}", name))?;
+        let Ok(tag) = reference.peel_to_tag() else {
+            continue;
+        };
+        let commit = tag.target()?.peel_to_commit()?;
+        let tagger = tag.tagger();
+        snapshots.push(Snapshot {
+            name:      String::from(name),
+            revision:  commit.id().to_string(),
+            author:    tagger
+                .as_ref()
+                .and_then(|t| t.name())
+                .unwrap_or_default()
+                .to_string(),
+            email:     tagger
+                .as_ref()
+                .and_then(|t| t.email())
+                .unwrap_or_default()
+                .to_string(),
+            timestamp: tagger
+                .as_ref()
+                .map(|t| t.when().seconds())
+                .unwrap_or_else(|| commit.time().seconds()),
+            message:   tag.message().unwrap_or_default().trim_end().to_string(),
+        });
+    }
+    snapshots.sort_by_key(|s| std::cmp::Reverse(s.timestamp));
+    Ok(snapshots)
+}
+
+fn find<'r>(repo: &'r Repository, name: &str) -> Result<Commit<'r>> {
+    verify_name(name)?;
+    let Ok(reference) = repo.find_reference(&format!("refs/tags/{}", name)) else {
+        return err!(LinksError::RevisionNotFound(String::from(name)));
+    };
+    Ok(reference.peel_to_commit()?)
+}
+
+/// The commit of the snapshot, checked before the archive starts to go out
+pub fn commit_of(repo_dir: &str, name: &str) -> Result<Oid> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let id = find(&repo, name)?.id();
+    Ok(id)
+}
+
+/// Writes the documents of the snapshot as a tar.gz, inside a directory named after it
+pub fn archive<W: Write>(repo_dir: &str, name: &str, out: W) -> Result<W> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let commit = find(&repo, name)?;
+    let mtime = u64::try_from(commit.time().seconds()).unwrap_or_default();
+    let mut archive = Archive::new(Format::TarGz, out, mtime);
+    archive::append_tree(&mut archive, &repo, &commit.tree()?, name)?;
+    archive.finish()
+}
+
+fn error_response(
+    what: &str,
+    e: lib_hyper_organizator::typedef::GenericError,
+) -> Result<Response<Body>> {
+    match e.downcast_ref() {
+        Some(LinksError::BadSnapshotName(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::BAD_REQUEST),
+        Some(LinksError::RevisionNotFound(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::NOT_FOUND),
+        Some(LinksError::SnapshotExists(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::CONFLICT),
+        Some(LinksError::NotOwner(_)) => e
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN),
+        _ => {
+            error!("Failed to {}: {}", what, e);
+            e.to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub async fn get_snapshots(_req: Request<Body>) -> Result<Response<Body>> {
+    match git_worker::run(|| list(&CONFIG.storage_dir)).await {
+        Ok(mut snapshots) => {
+            for snapshot in snapshots.iter_mut() {
+                snapshot.author = String::from(CONFIG.cn_of(&snapshot.author, &snapshot.email));
+            }
+            serde_json::to_string(&snapshots)?.to_json_response()
+        }
+        Err(e) => error_response("list the snapshots", e),
+    }
+}
+
+/// Owners take snapshots, the catalog and the documents are then viewable with `at=name`
+pub async fn create_snapshot(mut request: Request<Body>) -> Result<Response<Body>> {
+    let snapshot: NewSnapshot = match parse_body(&mut request).await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let cn = String::from(get_user_name(&request)?);
+    if !CONFIG.is_owner(&cn) {
+        return error_response("create the snapshot", Box::new(LinksError::NotOwner(cn)));
+    }
+    let result = git_worker::run(move || {
+        let message = snapshot.message.unwrap_or_else(|| snapshot.name.clone());
+        create(
+            &CONFIG.storage_dir,
+            &snapshot.name,
+            &CONFIG.signature(&cn)?,
+            &message,
+        )
+    })
+    .await;
+    match result {
+        Ok(revision) => Ok(Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Content-Type", "application/json")
+            .body(Body::from(format!(r#"{{"revision":"{}"}}"#, revision)))?),
+        Err(e) => error_response("create the snapshot", e),
+    }
+}
+
+/// `/snapshot_archive?name`
+pub async fn get_snapshot_archive(req: Request<Body>) -> Result<Response<Body>> {
+    let (Some(name), _) = parse_query(req.uri().query()) else {
+        return "no snapshot supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
+    };
+    let name = String::from(name);
+    let file_name = format!("links-{}.tar.gz", name);
+    let snapshot = name.clone();
+    if let Err(e) = git_worker::run(move || commit_of(&CONFIG.storage_dir, &snapshot)).await {
+        return error_response("archive the snapshot", e);
+    }
+    let body = streamed_body("snapshot archive", move |out| {
+        archive(&CONFIG.storage_dir, &name, out)
+    });
+    Ok(Response::builder()
+        .header("Content-Type", Format::TarGz.content_type())
+        .header(
+            "Content-Disposition",
+            format!(r#"attachment; filename="{}""#, file_name),
+        )
+        .body(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history, save_to_git::tests::test_repo};
+    use std::fs;
+
+    #[test]
+    fn test_snapshots() {
+        let uuid = "8b1e0c2b-3a4f-4b5c-8d6e-9f0a1b2c3d4e";
+        let file_name = format!("{}.md", uuid);
+        let dir = test_repo("snapshots", &file_name, "# Before\n");
+        let tagger = Signature::now("alice", "_").unwrap();
+
+        assert!(create(&dir, "../x", &tagger, "bad").is_err());
+        let revision = create(&dir, "before-reorg", &tagger, "before the reorg").unwrap();
+        assert!(create(&dir, "before-reorg", &tagger, "again").is_err());
+
+        fs::write(Path::new(&dir).join(&file_name), "# After\n").unwrap();
+        crate::save_to_git::commit(&dir, &[&file_name], &tagger, "reorg").unwrap();
+
+        let snapshots = list(&dir).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].name, "before-reorg");
+        assert_eq!(snapshots[0].revision, revision.to_string());
+        assert_eq!(snapshots[0].message, "before the reorg");
+        assert_eq!(snapshots[0].author, "alice");
+
+        let (content, id) = history::document_at(&dir, uuid, "before-reorg").unwrap();
+        assert_eq!((content.as_str(), id), ("# Before\n", revision));
+        assert!(!archive(&dir, "before-reorg", Vec::new())
+            .unwrap()
+            .is_empty());
+        assert!(archive(&dir, "missing", Vec::new()).is_err());
+        assert_eq!(commit_of(&dir, "before-reorg").unwrap(), revision);
+        assert!(commit_of(&dir, "missing").is_err());
+    }
+}
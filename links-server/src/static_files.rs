@@ -1,10 +1,12 @@
+use git2::Oid;
 use hyper::{Body, Request, Response, StatusCode};
 use lib_hyper_organizator::response_utils::IntoResultHyperResponse;
 use tokio::fs::read;
 use tracing::info;
 
-use crate::router::CONFIG;
-use crate::utils::Result;
+use crate::router::{LinksError, CONFIG};
+use crate::utils::{parse_query, Result};
+use crate::{git_worker, history};
 
 pub async fn serve_file(req: Request<Body>) -> Result<Response<Body>> {
     info!("serve_file");
@@ -48,21 +50,48 @@ fn to_response(content: String, mime: &str) -> Response<Body> {
 }
 
 async fn serve_links_file(req: Request<Body>) -> Result<Response<Body>> {
-    // the parameter req is the uuid of the file name
-    let Some(uuid) = req.uri().query() else {
+    // the query is the uuid of the file name, optionally followed by a revision or date
+    let (Some(uuid), params) = parse_query(req.uri().query()) else {
         return "no uuid supplied".to_text_response_with_status(StatusCode::BAD_REQUEST);
     };
     // check the uuid is valid
 
-    let file_name = format!("{}/{}.md", CONFIG.storage_dir, uuid);
-    info!("serve_links_file: {}", file_name);
-    match read(&file_name).await {
-        Ok(content) => {
-            let content = String::from_utf8(content)?;
-            Ok(to_response(content, "text/markdown"))
+    if let Some(at) = params.get("at") {
+        return serve_links_file_at(uuid, at).await;
+    }
+
+    info!("serve_links_file: {}", uuid);
+    // the editor sends the revision back on save so concurrent edits can be merged,
+    // it has to be the one the content was read from
+    let uuid = String::from(uuid);
+    let document =
+        git_worker::run(move || history::head_document(&CONFIG.storage_dir, &uuid)).await;
+    document_response(document)
+}
+
+/// Serves the document as it was in an older revision, straight from the git object store
+async fn serve_links_file_at(uuid: &str, at: &str) -> Result<Response<Body>> {
+    info!("serve_links_file: {} at {}", uuid, at);
+    let (uuid, at) = (String::from(uuid), String::from(at));
+    let document =
+        git_worker::run(move || history::document_at(&CONFIG.storage_dir, &uuid, &at)).await;
+    document_response(document)
+}
+
+fn document_response(document: Result<(String, Oid)>) -> Result<Response<Body>> {
+    match document {
+        Ok((content, revision)) => {
+            let mut response = to_response(content, "text/markdown");
+            response.headers_mut().insert("X-Revision", revision.to_string().parse()?);
+            Ok(response)
         }
-        Err(e) => e
-            .to_string()
-            .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(e) => match e.downcast_ref() {
+            Some(LinksError::DocumentNotFound(_)) | Some(LinksError::RevisionNotFound(_)) => e
+                .to_string()
+                .to_text_response_with_status(StatusCode::NOT_FOUND),
+            _ => e
+                .to_string()
+                .to_text_response_with_status(StatusCode::INTERNAL_SERVER_ERROR),
+        },
     }
 }
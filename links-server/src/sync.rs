@@ -0,0 +1,388 @@
+use std::{path::Path, sync::Mutex, time::Duration};
+
+use git2::{build::CheckoutBuilder, ErrorCode, Index, Oid, Repository, Signature};
+use hyper::{Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use lib_hyper_organizator::response_utils::{parse_body, IntoResultHyperResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    folders, git_worker, locks, push,
+    router::{LinksError, CONFIG},
+    save_to_git::{self, ConflictHunk},
+    utils::{get_epoch_ms, get_user_name, Result},
+};
+
+/// Where the fetched branch is kept, next to the local branches
+const SYNC_REFS: &str = "refs/remotes/sync";
+
+lazy_static! {
+    static ref STATUS: Mutex<SyncStatus> = Mutex::new(SyncStatus::default());
+}
+
+/// A file both sides changed in incompatible ways. The local version was kept,
+/// the remote content is here so somebody can fold it in by hand.
+#[derive(Serialize, Debug)]
+pub struct SyncConflict {
+    pub id:              u64,
+    pub path:            String,
+    pub remote_revision: String,
+    pub remote_content:  String,
+    pub hunks:           Vec<ConflictHunk>,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct SyncStatus {
+    remote:        Option<String>,
+    /// epoch ms
+    last_sync:     Option<u128>,
+    last_revision: Option<String>,
+    last_error:    Option<String>,
+    conflicts:     Vec<SyncConflict>,
+    next_id:       u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SyncOutcome {
+    UpToDate,
+    FastForward(Oid),
+    Merged(Oid),
+}
+
+#[derive(Deserialize, Debug)]
+struct Dismiss {
+    id: u64,
+}
+
+/// Starts the periodic sync when a sync remote is configured
+pub fn start() {
+    let Some(remote) = CONFIG.sync_remote.clone() else {
+        return;
+    };
+    STATUS.lock().unwrap().remote = Some(remote.clone());
+    let period = Duration::from_secs(CONFIG.sync_interval_secs);
+    info!("syncing with {} every {:?}", remote, period);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            sync_once(&remote).await;
+        }
+    });
+}
+
+/// Fetches, merges and pushes. The network is slow or away at times, so only the merge
+/// keeps the saves waiting.
+async fn sync_steps(remote: &str) -> Result<(SyncOutcome, Vec<SyncConflict>)> {
+    let url = remote.to_string();
+    let fetched = git_worker::run(move || Ok(fetch(&CONFIG.storage_dir, &url)?)).await?;
+    let merged = match fetched {
+        Some(theirs) => {
+            // saves must not touch the working tree while it is being merged
+            let _guard = locks::lock_working_tree().await;
+            let url = remote.to_string();
+            git_worker::run(move || {
+                let mut conflicts = Vec::new();
+                let outcome = merge_remote(&CONFIG.storage_dir, &url, theirs, &mut conflicts)?;
+                Ok((outcome, conflicts))
+            })
+            .await?
+        }
+        // nothing on the remote yet, pushing will create it
+        None => (SyncOutcome::UpToDate, Vec::new()),
+    };
+    let url = remote.to_string();
+    git_worker::run(move || Ok(push::push_to(&CONFIG.storage_dir, &url)?)).await?;
+    Ok(merged)
+}
+
+async fn sync_once(remote: &str) {
+    let result = sync_steps(remote).await;
+
+    let mut status = STATUS.lock().unwrap();
+    status.last_sync = Some(get_epoch_ms());
+    match result {
+        Ok((outcome, conflicts)) => {
+            info!("sync with {}: {:?}", remote, outcome);
+            status.last_error = None;
+            if let SyncOutcome::FastForward(id) | SyncOutcome::Merged(id) = outcome {
+                status.last_revision = Some(id.to_string());
+            }
+            for mut conflict in conflicts {
+                warn!("sync conflict in {}", conflict.path);
+                status.next_id += 1;
+                conflict.id = status.next_id;
+                status.conflicts.push(conflict);
+            }
+        }
+        Err(e) => {
+            warn!("sync with {} failed: {}", remote, e);
+            status.last_error = Some(e.to_string());
+        }
+    }
+}
+
+/// Fetches the current branch from the remote, `None` when the remote does not have it yet.
+/// Only the tracking reference is updated, the working tree is not touched.
+pub fn fetch(repo_dir: &str, url: &str) -> std::result::Result<Option<Oid>, git2::Error> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    let head = repo.head()?;
+    let (Some(branch), Some(short)) = (head.name(), head.shorthand()) else {
+        return Err(git2::Error::from_str("HEAD is not a branch"));
+    };
+    let tracking = format!("{}/{}", SYNC_REFS, short);
+
+    let mut remote = repo.remote_anonymous(url)?;
+    remote.fetch(&[format!("+{}:{}", branch, tracking)], None, None)?;
+    let theirs = match repo.find_reference(&tracking) {
+        Ok(theirs) => Some(theirs.peel_to_commit()?.id()),
+        Err(_) => None,
+    };
+    Ok(theirs)
+}
+
+/// Merges a fetched commit into the current branch and the working tree.
+/// Conflicting files keep the local content and are reported in `conflicts`.
+pub fn merge_remote(
+    repo_dir: &str,
+    url: &str,
+    theirs: Oid,
+    conflicts: &mut Vec<SyncConflict>,
+) -> std::result::Result<SyncOutcome, git2::Error> {
+    let repo = Repository::open(Path::new(repo_dir))?;
+    // the commits of the saves go through the index too, and none may be amended past the merge
+    let _guard = locks::lock_repository();
+    save_to_git::seal();
+    let their_commit = repo.find_commit(theirs)?;
+    let our_commit = repo.head()?.peel_to_commit()?;
+    let base = match repo.merge_base(our_commit.id(), their_commit.id()) {
+        Ok(base) => base,
+        // both sides were initialized on their own, no merge can ever bring them together
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            return Err(git2::Error::from_str(&format!(
+                "the local repository and {} have unrelated histories, \
+                 replace one of them with a clone of the other",
+                url
+            )));
+        }
+        Err(e) => return Err(e),
+    };
+
+    if base == their_commit.id() {
+        return Ok(SyncOutcome::UpToDate);
+    }
+    if base == our_commit.id() {
+        update_working_tree(&repo, repo_dir, their_commit.id(), "sync: fast forward")?;
+        return Ok(SyncOutcome::FastForward(their_commit.id()));
+    }
+
+    let mut index = repo.merge_commits(&our_commit, &their_commit, None)?;
+    if index.has_conflicts() {
+        keep_ours(&repo, &mut index, their_commit.id(), conflicts)?;
+    }
+    let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+    let author = Signature::now("links-server", "_")?;
+    let message = format!("sync: merged {} from {}", their_commit.id(), url);
+    let id = repo.commit(
+        None,
+        &author,
+        &author,
+        &message,
+        &tree,
+        &[&our_commit, &their_commit],
+    )?;
+    update_working_tree(&repo, repo_dir, id, "sync: merge")?;
+    Ok(SyncOutcome::Merged(id))
+}
+
+/// Checks out the commit and moves the current branch to it
+fn update_working_tree(
+    repo: &Repository,
+    repo_dir: &str,
+    id: Oid,
+    message: &str,
+) -> std::result::Result<(), git2::Error> {
+    let commit = repo.find_commit(id)?;
+    // the working tree matches HEAD, saves are committed right away, so forcing is safe
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force()))?;
+    repo.head()?.set_target(id, message)?;
+    // documents may have moved, the folders are read again from the new HEAD
+    folders::forget(repo_dir);
+    Ok(())
+}
+
+fn blob_content(repo: &Repository, id: Option<Oid>) -> std::result::Result<String, git2::Error> {
+    match id {
+        Some(id) => Ok(String::from_utf8_lossy(repo.find_blob(id)?.content()).into_owned()),
+        None => Ok(String::new()),
+    }
+}
+
+/// Resolves every conflict in favour of the local side and records what the remote wanted
+fn keep_ours(
+    repo: &Repository,
+    index: &mut Index,
+    remote_revision: Oid,
+    conflicts: &mut Vec<SyncConflict>,
+) -> std::result::Result<(), git2::Error> {
+    let entries = index
+        .conflicts()?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    for conflict in entries {
+        let Some(any) = conflict.our.as_ref().or(conflict.their.as_ref()) else {
+            continue;
+        };
+        let path = String::from_utf8_lossy(&any.path).into_owned();
+        let base = blob_content(repo, conflict.ancestor.as_ref().map(|e| e.id))?;
+        let ours = blob_content(repo, conflict.our.as_ref().map(|e| e.id))?;
+        let theirs = blob_content(repo, conflict.their.as_ref().map(|e| e.id))?;
+        conflicts.push(SyncConflict {
+            id:              0,
+            path:            path.clone(),
+            remote_revision: remote_revision.to_string(),
+            hunks:           save_to_git::conflict_hunks(&base, &ours, &theirs)?,
+            remote_content:  theirs,
+        });
+
+        for stage in 1..=3 {
+            let _ = index.remove(Path::new(&path), stage);
+        }
+        if let Some(mut our) = conflict.our {
+            // stage 0 is the resolved entry
+            our.flags &= !0x3000;
+            index.add(&our)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn get_sync_status(_req: Request<Body>) -> Result<Response<Body>> {
+    let status = serde_json::to_string(&*STATUS.lock().unwrap())?;
+    status.to_json_response()
+}
+
+/// Drops a conflict from the queue once an owner has dealt with it
+pub async fn dismiss_conflict(mut request: Request<Body>) -> Result<Response<Body>> {
+    let user = get_user_name(&request)?;
+    if !CONFIG.is_owner(user) {
+        return LinksError::NotOwner(String::from(user))
+            .to_string()
+            .to_text_response_with_status(StatusCode::FORBIDDEN);
+    }
+    let dismiss: Dismiss = match parse_body(&mut request).await {
+        Ok(dismiss) => dismiss,
+        Err(e) => {
+            return format!("Error parsing json: {}", e)
+                .to_text_response_with_status(StatusCode::BAD_REQUEST);
+        }
+    };
+    let mut status = STATUS.lock().unwrap();
+    let before = status.conflicts.len();
+    status.conflicts.retain(|c| c.id != dismiss.id);
+    if status.conflicts.len() == before {
+        return "no such conflict".to_text_response_with_status(StatusCode::NOT_FOUND);
+    }
+    "Conflict dismissed".to_text_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save_to_git::tests::test_repo;
+    use std::fs;
+
+    /// Our repository, a bare remote with the same history and a clone of that remote
+    fn setup(name: &str, content: &str) -> (String, String, String) {
+        let ours = test_repo(&format!("sync-{}", name), "doc.md", content);
+        let bare =
+            std::env::temp_dir().join(format!("links-sync-{}-bare-{}", name, std::process::id()));
+        let clone =
+            std::env::temp_dir().join(format!("links-sync-{}-clone-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&bare);
+        let _ = fs::remove_dir_all(&clone);
+        Repository::init_bare(&bare).unwrap();
+        let url = format!("file://{}", bare.display());
+        push::push_to(&ours, &url).unwrap();
+        Repository::clone(&url, &clone).unwrap();
+        (ours, url, clone.to_str().unwrap().to_string())
+    }
+
+    /// Both steps of a sync, without the push
+    fn sync_with(
+        repo_dir: &str,
+        url: &str,
+        conflicts: &mut Vec<SyncConflict>,
+    ) -> std::result::Result<SyncOutcome, git2::Error> {
+        match fetch(repo_dir, url)? {
+            Some(theirs) => merge_remote(repo_dir, url, theirs, conflicts),
+            None => Ok(SyncOutcome::UpToDate),
+        }
+    }
+
+    fn save(dir: &str, file_name: &str, content: &str) {
+        fs::write(Path::new(dir).join(file_name), content).unwrap();
+        let author = Signature::now("test", "_").unwrap();
+        save_to_git::commit(dir, &[file_name], &author, "saved via gui").unwrap();
+    }
+
+    #[test]
+    fn test_sync_merges_both_sides() {
+        let (ours, url, theirs) = setup("clean", "# Doc\n");
+        save(&ours, "doc.md", "# Doc\n- [a](http://a)\n");
+        save(&theirs, "other.md", "# Other\n");
+        push::push_to(&theirs, &url).unwrap();
+
+        let mut conflicts = Vec::new();
+        let outcome = sync_with(&ours, &url, &mut conflicts).unwrap();
+        assert!(matches!(outcome, SyncOutcome::Merged(_)));
+        assert!(conflicts.is_empty());
+        assert_eq!(
+            fs::read_to_string(Path::new(&ours).join("other.md")).unwrap(),
+            "# Other\n"
+        );
+        assert_eq!(
+            fs::read_to_string(Path::new(&ours).join("doc.md")).unwrap(),
+            "# Doc\n- [a](http://a)\n"
+        );
+        // a second sync has nothing to do
+        let outcome = sync_with(&ours, &url, &mut conflicts).unwrap();
+        assert_eq!(outcome, SyncOutcome::UpToDate);
+    }
+
+    #[test]
+    fn test_sync_unrelated_histories() {
+        let (ours, url, _) = setup("unrelated", "# Doc\n");
+        let other = test_repo("sync-unrelated-other", "doc.md", "# Other\n");
+        let bare = url.trim_start_matches("file://");
+        Repository::open_bare(bare)
+            .unwrap()
+            .find_reference("refs/heads/master")
+            .unwrap()
+            .delete()
+            .unwrap();
+        push::push_to(&other, &url).unwrap();
+
+        let e = sync_with(&ours, &url, &mut Vec::new()).unwrap_err();
+        assert!(e.message().contains("unrelated histories"), "{}", e);
+    }
+
+    #[test]
+    fn test_sync_queues_conflicts() {
+        let (ours, url, theirs) = setup("conflict", "# Doc\n- [a](http://a)\n");
+        save(&ours, "doc.md", "# Doc\n- [a](http://ours)\n");
+        save(&theirs, "doc.md", "# Doc\n- [a](http://theirs)\n");
+        push::push_to(&theirs, &url).unwrap();
+
+        let mut conflicts = Vec::new();
+        let outcome = sync_with(&ours, &url, &mut conflicts).unwrap();
+        assert!(matches!(outcome, SyncOutcome::Merged(_)));
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, "doc.md");
+        assert_eq!(conflicts[0].remote_content, "# Doc\n- [a](http://theirs)\n");
+        assert_eq!(
+            fs::read_to_string(Path::new(&ours).join("doc.md")).unwrap(),
+            "# Doc\n- [a](http://ours)\n"
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use hyper::{Body, Request};
 use lib_hyper_organizator::{authentication::check_security::UserId, typedef::GenericError};
@@ -22,3 +25,66 @@ pub fn get_user_name(request: &Request<Body>) -> Result<&str> {
     let user = &user_id.0;
     Ok(user)
 }
+
+/// Splits a query like `uuid&at=2024-01-01` into the bare leading value and the named parameters.
+/// The values of the named parameters are percent decoded.
+pub fn parse_query(query: Option<&str>) -> (Option<&str>, HashMap<&str, String>) {
+    let mut bare = None;
+    let mut params = HashMap::new();
+    for part in query.unwrap_or_default().split('&') {
+        match part.split_once('=') {
+            Some((name, value)) => {
+                params.insert(name, percent_decode(value));
+            }
+            None if bare.is_none() && !part.is_empty() => bare = Some(part),
+            None => (),
+        }
+    }
+    (bare, params)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        decoded.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        let (bare, params) = parse_query(Some("329f4aef&at=2024-01-01T10%3A30&x=a+b"));
+        assert_eq!(bare, Some("329f4aef"));
+        assert_eq!(params.get("at").unwrap(), "2024-01-01T10:30");
+        assert_eq!(params.get("x").unwrap(), "a b");
+
+        let (bare, params) = parse_query(None);
+        assert_eq!(bare, None);
+        assert!(params.is_empty());
+
+        let (bare, params) = parse_query(Some("at=HEAD~1"));
+        assert_eq!(bare, None);
+        assert_eq!(params.get("at").unwrap(), "HEAD~1");
+    }
+}